@@ -0,0 +1,340 @@
+//! Running commands on a norman server from other programs.
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use norman_protocol::{Capabilities, ClientKey, Health, NormanPacket, PresharedKey, RequestType, Service, Status, PROTOCOL_VERSION};
+
+use crate::{ClientError, Connection, Pipeline};
+
+/// A command for the server to run.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Command {
+    pub service: Service,
+    /// The command line, split into words by the server unless `shell` is set.
+    pub line: String,
+    /// Program and arguments to start directly instead of `line`.
+    pub argv: Vec<String>,
+    /// Shell to interpret `line` with, only for SHELL commands.
+    pub shell: Option<String>,
+    /// Wait for the command to finish and send back its output, rather than leaving it running.
+    pub return_output: bool,
+}
+
+impl Command {
+    /// A command line for the SHELL service.
+    pub fn new(line: &str) -> Command {
+        Command {
+            service: Service::SHELL,
+            line: line.to_string(),
+            argv: Vec::new(),
+            shell: None,
+            return_output: true,
+        }
+    }
+
+    /// A program and its arguments, started directly with nothing interpreted.
+    pub fn from_argv<S: AsRef<str>>(argv: &[S]) -> Command {
+        Command{argv: argv.iter().map(|arg| arg.as_ref().to_string()).collect(), ..Command::new("")}
+    }
+
+    pub fn with_service(mut self, service: Service) -> Command {
+        self.service = service;
+        self
+    }
+
+    /// Have `shell` interpret the command line.
+    pub fn with_shell(mut self, shell: &str) -> Command {
+        self.shell = Some(shell.to_string());
+        self
+    }
+
+    /// Leave the command running on the server instead of waiting for its output.
+    pub fn in_background(mut self) -> Command {
+        self.return_output = false;
+        self
+    }
+
+    /// The REQUEST packet asking for the command to be run.
+    pub fn packet(&self) -> NormanPacket {
+        let mut packet = NormanPacket::new(String::from(PROTOCOL_VERSION), self.return_output, self.service.clone(), RequestType::REQUEST, Status::OK, String::from("None"), self.line.clone(), false);
+        packet.data.argv = self.argv.clone();
+        packet.data.shell = self.shell.clone();
+        packet
+    }
+}
+
+impl From<&str> for Command {
+    fn from(line: &str) -> Command {
+        Command::new(line)
+    }
+}
+
+/// What a command sent back.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Output {
+    pub status: Status,
+    pub stdout: String,
+    pub stderr: String,
+    /// None unless the command ran to completion.
+    pub exit_code: Option<i32>,
+}
+
+impl Output {
+    /// Whether the command exited with 0, or was started in the background.
+    pub fn success(&self) -> bool {
+        matches!(self.exit_code, None | Some(0))
+    }
+}
+
+/// The server's answer to a ping.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Ping {
+    /// How long the reply took to arrive.
+    pub round_trip: Duration,
+    pub health: Health,
+}
+
+/// A connection to a server that commands are run over one after another.
+///
+/// ```no_run
+/// use norman_client::NormanClient;
+///
+/// let mut client = NormanClient::connect("build-01:9000")?;
+/// let output = client.execute("uptime")?;
+/// print!("{}", output.stdout);
+/// # Ok::<(), norman_client::ClientError>(())
+/// ```
+pub struct NormanClient {
+    pipeline: Pipeline<Connection>,
+}
+
+impl NormanClient {
+    /// Connect over plain TCP, waiting as long as it takes for the connection and every reply.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<NormanClient, ClientError> {
+        Ok(NormanClient::new(Connection::Plain(tcp_connect(addr, None)?)))
+    }
+
+    /// Connect over plain TCP, giving up if connecting or any reply takes longer than `timeout`.
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<NormanClient, ClientError> {
+        Ok(NormanClient::new(Connection::Plain(tcp_connect(addr, Some(timeout))?)))
+    }
+
+    /// Talk to the server over a connection that is already open, such as one over TLS.
+    pub fn new(connection: Connection) -> NormanClient {
+        NormanClient{pipeline: Pipeline::new(connection)}
+    }
+
+    /// Encrypt requests and decrypt replies with `key`.
+    pub fn with_key(self, key: PresharedKey) -> NormanClient {
+        NormanClient{pipeline: self.pipeline.with_key(key)}
+    }
+
+    /// Sign requests as `client_id`.
+    pub fn with_auth(self, client_id: String, key: ClientKey) -> NormanClient {
+        NormanClient{pipeline: self.pipeline.with_auth(client_id, key)}
+    }
+
+    /// Give up on sending a request or waiting for its reply after `timeout`, or never if it is None.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<(), ClientError> {
+        let stream = self.pipeline.get_ref().tcp_stream();
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Run a command and wait for what it sends back.
+    ///
+    /// A command that ran but failed is still an `Output`, with its exit code.
+    /// It is only an error if the server wouldn't or couldn't run it.
+    pub fn execute<C: Into<Command>>(&mut self, command: C) -> Result<Output, ClientError> {
+        let reply = self.request(command.into().packet())?;
+        let status = reply.meta.status;
+
+        match reply.data.exit_code.is_some() || status.is_success() {
+            true => Ok(Output{status, stdout: reply.data.data, stderr: reply.data.stderr, exit_code: reply.data.exit_code}),
+            false => Err(ClientError::Status(status, reply.data.data)),
+        }
+    }
+
+    /// Check the server is up without running anything.
+    pub fn ping(&mut self) -> Result<Ping, ClientError> {
+        let sent_at = Instant::now();
+        let reply = self.request(ping_request())?;
+        let round_trip = sent_at.elapsed();
+
+        let health = parse_reply(reply, Status::PING)?;
+        Ok(Ping{round_trip, health})
+    }
+
+    /// Ask the server what it supports.
+    pub fn capabilities(&mut self) -> Result<Capabilities, ClientError> {
+        let reply = self.request(capabilities_request())?;
+        parse_reply(reply, Status::OK)
+    }
+
+    /// Send a request packet and wait for the reply to it, whatever its status.
+    pub fn request(&mut self, packet: NormanPacket) -> Result<NormanPacket, ClientError> {
+        let uid = self.pipeline.send(packet)?;
+        self.pipeline.receive_for(uid)
+    }
+}
+
+/// The TEST packet checking a server is up.
+pub fn ping_request() -> NormanPacket {
+    NormanPacket::new(String::from(PROTOCOL_VERSION), true, Service::SHELL, RequestType::TEST, Status::PING, String::from("None"), String::new(), false)
+}
+
+/// The CAPABILITIES packet asking a server what it supports.
+pub fn capabilities_request() -> NormanPacket {
+    NormanPacket::new(String::from(PROTOCOL_VERSION), true, Service::SHELL, RequestType::CAPABILITIES, Status::OK, String::from("None"), String::new(), false)
+}
+
+/// Open a TCP connection, trying each address `addr` resolves to in turn.
+///
+/// With a timeout, each attempt to connect is given that long, and so is
+/// every read and write once connected.
+pub fn tcp_connect<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let stream = match timeout {
+        Some(timeout) => {
+            let mut last_error = io::Error::new(io::ErrorKind::NotFound, "Host did not resolve to any address");
+            let mut stream = None;
+            for address in addr.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&address, timeout) {
+                    Ok(connected) => {
+                        stream = Some(connected);
+                        break;
+                    },
+                    Err(error) => last_error = error,
+                }
+            }
+            stream.ok_or(last_error)?
+        },
+        None => TcpStream::connect(addr)?,
+    };
+
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    Ok(stream)
+}
+
+/// Read the data of a reply that should carry `expected` as its status.
+fn parse_reply<T: std::str::FromStr<Err = norman_protocol::ParseError>>(reply: NormanPacket, expected: Status) -> Result<T, ClientError> {
+    match reply.meta.status == expected {
+        true => reply.data.data.parse().map_err(ClientError::BadReply),
+        false => Err(ClientError::Status(reply.meta.status, reply.data.data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use norman_protocol::{PacketReader, WireFormat};
+
+    /// A server that answers each request on one connection with whatever `answer` makes of it.
+    fn mock_server<F: Fn(&NormanPacket) -> Option<NormanPacket> + Send + 'static>(answer: F) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = PacketReader::new(stream);
+            while let Ok(Some(request)) = reader.read_packet() {
+                if let Some(reply) = answer(&request) {
+                    reader.get_mut().write_all(&reply.with_uid(request.meta.uid).encode(WireFormat::Framed)).unwrap();
+                }
+            }
+        });
+
+        address
+    }
+
+    fn reply(req_type: RequestType, status: Status, data: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, req_type, status, String::from("None"), data.to_string(), false)
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::new("uptime").packet().data.data, "uptime");
+
+        let packet = Command::from_argv(&["ls", "-l"]).with_service(Service::DOCKER).in_background().packet();
+        assert_eq!(packet.data.argv, vec!["ls", "-l"]);
+        assert_eq!(packet.header.service, Service::DOCKER);
+        assert!(!packet.header.return_output);
+
+        assert_eq!(Command::from("echo $HOME").with_shell("bash").packet().data.shell, Some(String::from("bash")));
+    }
+
+    #[test]
+    fn execute() {
+        let address = mock_server(|request| {
+            let mut answer = match request.data.data.as_str() {
+                "refused" => return Some(reply(RequestType::ERROR, Status::FORBIDDEN, "Not for you")),
+                "false" => reply(RequestType::RETURN, Status::INTERNAL_ERROR, ""),
+                command => reply(RequestType::RETURN, Status::OK, &format!("ran {}\n", command)),
+            };
+            answer.data.exit_code = Some(match answer.meta.status {
+                Status::OK => 0,
+                _ => 1,
+            });
+            Some(answer)
+        });
+        let mut client = NormanClient::connect(address).unwrap();
+
+        let output = client.execute("uptime").unwrap();
+        assert_eq!(output.stdout, "ran uptime\n");
+        assert!(output.success());
+
+        let output = client.execute("false").unwrap();
+        assert_eq!(output.exit_code, Some(1));
+        assert!(!output.success());
+
+        match client.execute("refused") {
+            Err(ClientError::Status(status, message)) => assert_eq!((status, message.as_str()), (Status::FORBIDDEN, "Not for you")),
+            other => panic!("Expected a FORBIDDEN status, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ping_and_capabilities() {
+        let address = mock_server(|request| match request.meta.req_type {
+            RequestType::TEST => Some(reply(RequestType::TEST, Status::PING, "Version: NORMAN/0.1\nSoftware: norman-server 0.1.0\nUptime: 90s\nWorkers: 4\nBusy workers: 1\nQueued: 0")),
+            _ => Some(reply(RequestType::RETURN, Status::OK, "Version: NORMAN/0.1")),
+        });
+        let mut client = NormanClient::connect(address).unwrap();
+
+        let ping = client.ping().unwrap();
+        assert_eq!(ping.health.uptime, Duration::from_secs(90));
+        assert_eq!(ping.health.busy_workers, 1);
+
+        assert!(matches!(client.capabilities(), Err(ClientError::BadReply(_))));
+    }
+
+    #[test]
+    fn timeout() {
+        let address = mock_server(|_| None);
+        let mut client = NormanClient::connect_timeout(address, Duration::from_millis(50)).unwrap();
+
+        assert!(matches!(client.ping(), Err(ClientError::Timeout)));
+    }
+
+    #[test]
+    fn late_replies_are_not_taken_for_later_ones() {
+        let address = mock_server(|request| {
+            if request.data.data == "slow" {
+                thread::sleep(Duration::from_millis(200));
+            }
+            Some(reply(RequestType::RETURN, Status::OK, &request.data.data))
+        });
+        let mut client = NormanClient::connect_timeout(address, Duration::from_millis(50)).unwrap();
+
+        assert!(matches!(client.execute("slow"), Err(ClientError::Timeout)));
+        client.set_timeout(None).unwrap();
+        assert_eq!(client.execute("fast").unwrap().stdout, "fast");
+    }
+}
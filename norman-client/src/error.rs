@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use norman_protocol::{CryptoError, ParseError, ReadError, ReassemblyError, Status};
+
+/// The ways talking to a server can fail.
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// No reply arrived before the read timeout.
+    Timeout,
+    /// The server hung up with requests still unanswered.
+    Closed,
+    /// A reply could not be read.
+    Read(ReadError),
+    /// A reply could not be decrypted with the client's key.
+    Decrypt(CryptoError),
+    /// The fragments of a reply arrived out of order.
+    Reassembly(ReassemblyError),
+    /// The server couldn't read one of the requests, so couldn't say which it was answering.
+    Rejected(String),
+    /// A reply came back in the clear when requests are encrypted, so it can't be trusted.
+    Unencrypted(Status, String),
+    /// The server answered with an error status and a message saying why.
+    Status(Status, String),
+    /// A reply said something that couldn't be made sense of.
+    BadReply(ParseError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(error) => write!(f, "Problem talking to the server: {}", error),
+            ClientError::Timeout => write!(f, "Timed out waiting for a response"),
+            ClientError::Closed => write!(f, "Remote host closed the connection without responding"),
+            ClientError::Read(error) => write!(f, "Problem reading response: {}", error),
+            ClientError::Decrypt(error) => write!(f, "Problem decrypting response: {}", error),
+            ClientError::Reassembly(error) => write!(f, "Problem reassembling response: {}", error),
+            ClientError::Rejected(message) => write!(f, "Server rejected a request: {}", message),
+            ClientError::Unencrypted(status, message) => write!(f, "Server sent an unencrypted reply, which can't be trusted: {}: {}", status, message),
+            ClientError::Status(status, message) => write!(f, "Server returned {}: {}", status, message),
+            ClientError::BadReply(error) => write!(f, "Problem reading the server's reply: {}", error),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Io(error) => Some(error),
+            ClientError::Read(error) => Some(error),
+            ClientError::Decrypt(error) => Some(error),
+            ClientError::Reassembly(error) => Some(error),
+            ClientError::BadReply(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> ClientError {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout,
+            _ => ClientError::Io(error),
+        }
+    }
+}
+
+impl From<ReadError> for ClientError {
+    fn from(error: ReadError) -> ClientError {
+        match error {
+            ReadError::Io(error) => ClientError::from(error),
+            error => ClientError::Read(error),
+        }
+    }
+}
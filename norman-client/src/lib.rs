@@ -1,411 +1,400 @@
-//Packet Structure
-
-#[derive(PartialEq, Clone, Debug)]
-pub enum Service {
-    SHELL,
-    DOCKER,
-    AWS,
-    UNKNOWN,
-}
-
-#[derive(PartialEq, Clone, Debug)]
-pub enum RequestType{
-    REQUEST,
-    RETURN,
-    TEST,
-    ERROR,
-}
-
-#[derive(PartialEq, Clone, Debug)]
-pub enum Status {
-    FINE{code: i32},
-    ERROR{code: i32},
-    TEST{code:i32},
-    MALFORMED{code:i32},
-}
-
-#[derive(PartialEq, Clone, Debug)]
-pub struct Header {
-    pub version: String, //Format NORMAN/<ver>
-    pub return_output: bool,
-    pub service: Service,
-}
-
-#[derive(PartialEq, Clone, Debug)]
-pub struct Metadata {
-    pub req_type: RequestType,
-    pub status: Status,
-    pub uid: i32,
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use std::{fmt, fs};
+
+use norman_protocol::{ClientKey, PresharedKey, Service, SHELLS};
+
+mod client;
+mod error;
+mod pipeline;
+mod tls;
+
+pub use client::{capabilities_request, ping_request, tcp_connect, Command, NormanClient, Output, Ping};
+pub use error::ClientError;
+pub use pipeline::{Pipeline, DEFAULT_WINDOW};
+pub use tls::{Connection, TlsOptions};
+
+pub const USAGE: &str = "Usage: norman-client [options] <ip> <port> [command...]
+       norman-client [options] capabilities <ip> <port>
+       norman-client [options] ping <ip> <port>
+
+Runs a command on the norman server at <ip>:<port>. If no command is given it is read from stdin.
+The command's words are passed to the program as they are, with no shell involved, unless
+--shell is given.
+
+With capabilities, asks the server what it supports instead: its protocol version, name,
+services, encryption types and largest request size. With ping, checks the server is up
+without running anything, printing the round-trip time, the server's uptime and how busy it is.
+
+Options:
+    -s, --service <name>   Service to send the command to, e.g. SHELL, DOCKER or AWS (default SHELL)
+    -n, --no-output        Run the command in the background instead of waiting for its output
+    -S, --shell <shell>    Run the command as a line for sh, bash or zsh to interpret (SHELL only)
+    -t, --timeout <secs>   Give up if connecting or waiting for a response takes longer than this
+    -f, --format <format>  How to print the response: plain, verbose or raw (default plain)
+    -k, --key-file <file>  Encrypt the request with the pre-shared key in this file, written as hex
+    -u, --client-id <id>   Sign the request as this client. Needs --client-key-file
+        --client-key-file <file>
+                           File holding this client's signing key, written as hex
+        --tls-ca <file>    Connect over TLS, trusting server certificates signed by this PEM CA
+        --tls-cert <file>  PEM certificate identifying this machine to the server
+        --tls-key <file>   PEM private key for --tls-cert
+        --tls-name <name>  Name to check the server certificate against (default <ip>)
+    -h, --help             Print this message";
+
+/// What the client asks the server to do.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Action {
+    /// Run a command.
+    Run,
+    /// Describe what the server supports.
+    Capabilities,
+    /// Check the server is answering, and how quickly.
+    Ping,
 }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Encryption {
-    pub encoding_type: String,
-    pub key: String,
+pub struct Target {
+    pub ip: String,
+    pub port: u16,
 }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Data {
-    pub data: String,
+/// How the response is printed.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Just the response data.
+    Plain,
+    /// The response status and uid, then the data.
+    Verbose,
+    /// The response packet in the NORMAN/0.1 text format.
+    Raw,
 }
 
 #[derive(PartialEq, Clone, Debug)]
-pub struct Terminator {
-    pub multi_packet: bool,
-    pub term_string: String,
+pub enum UsageError {
+    HelpRequested,
+    Invalid(String),
 }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct NormanPacket {
-    pub header: Header,
-    pub meta: Metadata,
-    pub encryption: Encryption,
-    pub data: Data,
-    pub terminator: Terminator, //Should be NORMAN/END
-}
-
-impl NormanPacket {
-    pub fn new (ver: String, ret_out: bool, service: Service, req_type: RequestType, status: Status, enc_type: String, data: String, multi_packet: bool) -> NormanPacket {
-        NormanPacket {
-            header: Header {
-                version: ver,
-                return_output: ret_out,
-                service: service,
-            },
-            meta: Metadata {
-                req_type: req_type,
-                status: status,
-                uid: 0,
-            },
-            encryption: Encryption {
-                encoding_type: enc_type,
-                key: String::from(" "),
-            },
-            data: Data {
-                data,
-            },
-            terminator: Terminator {
-                multi_packet: multi_packet,
-                term_string: String::from("NORMAN/END"),
-            },
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsageError::HelpRequested => write!(f, "{}", USAGE),
+            UsageError::Invalid(message) => write!(f, "{}", message),
         }
     }
-
-    pub fn as_string(&self) -> String {
-        let mut packet_string: String = String::new();
-
-        //Concatenate Header
-        packet_string = packet_string + 
-            //Version
-            &self.header.version + "|" +
-            //Return Flag
-            match &self.header.return_output{
-                true => "true",
-                false => "false",
-            } + "|" +
-            //Target Service
-            match &self.header.service {
-                Service::AWS => "AWS",
-                Service::DOCKER => "DOCKER",
-                Service::SHELL => "SHELL",
-                Service::UNKNOWN => "UNKNOWN"
-            } + "|";
-        
-        //Concatenate Metadata
-        packet_string = packet_string + 
-            //Type of packet
-            match &self.meta.req_type {
-                RequestType::REQUEST => "REQUEST",
-                RequestType::RETURN => "RETURN",
-                RequestType::TEST => "TEST",
-                RequestType::ERROR => "ERROR",
-            } + "|" +
-            //Status of packet
-            match &self.meta.status {
-                Status::FINE{code: 200} => "200 OK",
-                Status::ERROR{code: 500} => "500 ERR",
-                Status::TEST{code: 100} => "100 TEST",
-                _ => "505 MALFORMED"
-            } + "|" +
-            //Packet ID
-            &self.meta.uid.to_string()  + "|";
-        //Concatenate Encryption
-            packet_string = packet_string +
-            //Encryption Type
-            &self.encryption.encoding_type + "|" +
-            &self.encryption.key + "|";
-        //Concatenate Data
-        packet_string = packet_string +
-            //Packet Data
-            &self.data.data + "|";
-
-        //Concatenate Terminator
-        packet_string = packet_string +
-            //Concatenated packet flag
-            match &self.terminator.multi_packet{
-                true => "true",
-                false => "false",
-            } + "|" +
-            //Packet terminator
-            &self.terminator.term_string;
-
-        packet_string
-    }
-
-    pub fn from_string (packet_string: String) -> NormanPacket {
-        let mut packet_components = packet_string.split("|");
-
-        let version: String;
-        let return_output: bool;
-        let service: Service;
-        
-        let req_type: RequestType;
-        let status: Status;
-        
-        let encoding_type: String;
-        
-        let data: String;
-        
-        let multi_packet: bool;
-        
-        if packet_components.clone().count() == 11 {
-            version = packet_components.next().unwrap().to_string();
-            return_output = match packet_components.next().unwrap() {
-                "true" => true,
-                "false" => false,
-                _ => true
-            };
-            service = match packet_components.next().unwrap() {
-                "SHELL" => Service::SHELL,
-                "AWS" => Service::AWS,
-                "DOCKER" => Service::DOCKER,
-                _ => Service::UNKNOWN,
-            };
-
-            req_type = match packet_components.next().unwrap() {
-                "REQUEST" => RequestType::REQUEST,
-                "RETURN" => RequestType::RETURN,
-                "TEST" => RequestType::TEST,
-                _ => RequestType::ERROR,
-            };
-            status = match packet_components.next().unwrap() {
-                "200 OK" => Status::FINE{code: 200},
-                "500 ERR" => Status::ERROR{code: 500},
-                "100 TEST" => Status::TEST{code: 100},
-                _ => Status::MALFORMED{code: 505},
-            };
-
-            packet_components.next();
-
-            encoding_type = packet_components.next().unwrap().to_string();
-
-            packet_components.next();
-
-            data = packet_components.next().unwrap().to_string();
-
-            multi_packet = match packet_components.next().unwrap() {
-                "true" => true,
-                "false" => false,
-                _ => false,
-            };
-        } else {
-            let component_count = packet_components.clone().count();
-            let mut panic_string = String::new();
-            for component in packet_components {
-                panic_string = format!("{}{}{}", panic_string, component, String::from(","));
-            }
-            panic!("Malformed packet! Expeceted 9 components but found {}. Packet read {}", component_count, panic_string);
-        }
-
-        NormanPacket::new(version, return_output, service, req_type, status, encoding_type, data, multi_packet)
-    }
-}
-
-
-pub struct Target {
-    pub ip: String,
-    pub port: String,
 }
 
 //Parse User Input
 pub struct UserOptions {
+    pub action: Action,
     pub target: Target,
+    /// The program and arguments to run, or empty if the command should be read from stdin.
+    pub command: Vec<String>,
+    /// Shell to interpret the command with, rather than running it directly.
+    pub shell: Option<String>,
+    pub service: Service,
+    pub return_output: bool,
+    pub timeout: Option<Duration>,
+    pub format: OutputFormat,
+    /// Key to encrypt the request and decrypt the response with.
+    pub key: Option<PresharedKey>,
+    /// Client id and key to sign the request with.
+    pub auth: Option<(String, ClientKey)>,
+    /// Connect over TLS instead of plain TCP.
+    pub tls: Option<TlsOptions>,
 }
 
 impl UserOptions {
-    pub fn new(mut args: std::env::Args) -> Result<UserOptions, &'static str> {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<UserOptions, UsageError> {
         args.next();
-        let ip = match args.next() {
+
+        let mut action = Action::Run;
+        let mut service = Service::SHELL;
+        let mut return_output = true;
+        let mut shell = None;
+        let mut timeout = None;
+        let mut format = OutputFormat::Plain;
+        let mut key = None;
+        let mut client_id = None;
+        let mut client_key = None;
+        let mut tls_ca = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_name = None;
+        let mut positional = Vec::new();
+
+        //Options are accepted up until the command starts
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(UsageError::HelpRequested),
+                "-n" | "--no-output" => return_output = false,
+                "-S" | "--shell" => {
+                    let value = option_value(&mut args, &arg)?;
+                    if !SHELLS.contains(&value.as_str()) {
+                        return Err(UsageError::Invalid(format!("Unsupported shell \"{}\". Expected one of {}", value, SHELLS.join(", "))));
+                    }
+                    shell = Some(value);
+                },
+                "-s" | "--service" => {
+                    service = Service::new(&option_value(&mut args, &arg)?).map_err(|error| UsageError::Invalid(error.to_string()))?;
+                },
+                "-t" | "--timeout" => {
+                    let value = option_value(&mut args, &arg)?;
+                    timeout = match value.parse::<f64>() {
+                        Ok(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+                        _ => return Err(UsageError::Invalid(format!("Timeout must be a positive number of seconds, not \"{}\"", value))),
+                    };
+                },
+                "-f" | "--format" => {
+                    format = match option_value(&mut args, &arg)?.as_str() {
+                        "plain" => OutputFormat::Plain,
+                        "verbose" => OutputFormat::Verbose,
+                        "raw" => OutputFormat::Raw,
+                        other => return Err(UsageError::Invalid(format!("Unknown output format \"{}\". Expected plain, verbose or raw", other))),
+                    };
+                },
+                "-k" | "--key-file" => {
+                    let path = option_value(&mut args, &arg)?;
+                    let contents = fs::read_to_string(&path).map_err(|error| UsageError::Invalid(format!("Problem reading key file {}: {}", path, error)))?;
+                    key = Some(PresharedKey::from_hex(&contents).map_err(|error| UsageError::Invalid(format!("Problem parsing key file {}: {}", path, error)))?);
+                },
+                "-u" | "--client-id" => {
+                    let id = option_value(&mut args, &arg)?;
+                    if id.is_empty() || id.contains(&[':', '|'][..]) {
+                        return Err(UsageError::Invalid(format!("Client id \"{}\" must be non-empty and free of ':' and '|'", id)));
+                    }
+                    client_id = Some(id);
+                },
+                "--client-key-file" => {
+                    let path = option_value(&mut args, &arg)?;
+                    let contents = fs::read_to_string(&path).map_err(|error| UsageError::Invalid(format!("Problem reading client key file {}: {}", path, error)))?;
+                    client_key = Some(ClientKey::from_hex(&contents).map_err(|error| UsageError::Invalid(format!("Problem parsing client key file {}: {}", path, error)))?);
+                },
+                "--tls-ca" => tls_ca = Some(option_value(&mut args, &arg)?),
+                "--tls-cert" => tls_cert = Some(option_value(&mut args, &arg)?),
+                "--tls-key" => tls_key = Some(option_value(&mut args, &arg)?),
+                "--tls-name" => tls_name = Some(option_value(&mut args, &arg)?),
+                "--" => {
+                    positional.extend(args.by_ref());
+                    break;
+                },
+                _ if arg.starts_with('-') && arg.len() > 1 => return Err(UsageError::Invalid(format!("Unknown option \"{}\"", arg))),
+                "capabilities" if positional.is_empty() && action == Action::Run => action = Action::Capabilities,
+                "ping" if positional.is_empty() && action == Action::Run => action = Action::Ping,
+                _ => {
+                    positional.push(arg);
+                    if positional.len() > 2 {
+                        positional.extend(args.by_ref());
+                        break;
+                    }
+                },
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let ip = match positional.next() {
             Some(arg) => arg,
-            None => return Err("No ip provided. \n Syntax: norman <ip> <port>"),
+            None => return Err(UsageError::Invalid(String::from("No ip provided"))),
         };
-        let port = match args.next() {
-            Some(arg) => arg,
-            None => return Err("No port provided \n Syntax: norman <ip> <port>")
+        let port = match positional.next() {
+            Some(arg) => match arg.parse() {
+                Ok(port) => port,
+                Err(_) => return Err(UsageError::Invalid(format!("Port must be a number between 0 and 65535, not \"{}\"", arg))),
+            },
+            None => return Err(UsageError::Invalid(String::from("No port provided"))),
         };
 
-        let target = Target{ip, port};
-
-        Ok(UserOptions{target})
-    }
-}
+        let command: Vec<String> = positional.collect();
+        if action != Action::Run && !command.is_empty() {
+            return Err(UsageError::Invalid(format!("Unexpected argument \"{}\". Only running a command takes one", command[0])));
+        }
+        if shell.is_some() && service != Service::SHELL {
+            return Err(UsageError::Invalid(String::from("A shell can only be chosen for the SHELL service")));
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::Rng;
-
-    fn gen_random_packet() -> NormanPacket {
-        let version: String;
-        let return_output: bool;
-        let service: Service;
-        
-        let req_type: RequestType;
-        let status: Status;
-        
-        let encoding_type: String;
-        
-        let data: String;
-        
-        let multi_packet: bool;
-
-        version = String::from("NORMAN/0.1");
-        return_output = match rand::thread_rng().gen_range(0, 2) {
-            1 => true,
-            0 => false,
-            _ => true
-        };
-        service = match rand::thread_rng().gen_range(0, 4) {
-            0 => Service::SHELL,
-            1 => Service::AWS,
-            2 => Service::DOCKER,
-            _ => Service::UNKNOWN,
+        let auth = match (client_id, client_key) {
+            (Some(id), Some(key)) => Some((id, key)),
+            (None, None) => None,
+            _ => return Err(UsageError::Invalid(String::from("Signing requests needs both --client-id and --client-key-file"))),
         };
 
-        req_type = match rand::thread_rng().gen_range(0, 4) {
-            0 => RequestType::REQUEST,
-            1 => RequestType::RETURN,
-            2 => RequestType::TEST,
-            _ => RequestType::ERROR,
+        let identity = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err(UsageError::Invalid(String::from("A TLS client certificate needs both --tls-cert and --tls-key"))),
         };
-        status = match rand::thread_rng().gen_range(0, 4) {
-            0 => Status::FINE{code: 200},
-            1 => Status::ERROR{code: 500},
-            2 => Status::TEST{code: 100},
-            _ => Status::MALFORMED{code: 505},
+        let tls = match tls_ca {
+            Some(ca) => Some(TlsOptions{ca, identity, server_name: tls_name}),
+            None if identity.is_some() || tls_name.is_some() => return Err(UsageError::Invalid(String::from("TLS options need --tls-ca"))),
+            None => None,
         };
 
-        encoding_type = String::from("None");
-
-        data = String::from("Random Packet Example Data");
-
-        multi_packet = match rand::thread_rng().gen_range(0, 2) {
-            1 => true,
-            0 => false,
-            _ => false,
-        };
+        let target = Target{ip, port};
 
-        NormanPacket::new(version, return_output, service, req_type, status, encoding_type, data, multi_packet)
+        Ok(UserOptions{action, target, command, shell, service, return_output, timeout, format, key, auth, tls})
     }
+}
 
-    #[test]
-    fn valid_packets() {
-        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+/// A uid for a new request.
+///
+/// Each call gives a different uid until the positive `i32`s run out and they
+/// start over. 0 is left for replies to packets the server couldn't read.
+pub fn next_uid() -> i32 {
+    static NEXT_UID: AtomicU32 = AtomicU32::new(0);
 
-        sentry::integrations::panic::register_panic_handler();
+    (NEXT_UID.fetch_add(1, Ordering::Relaxed) % i32::MAX as u32) as i32 + 1
+}
 
-        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
-        let aws_packet = NormanPacket::new("NORMAN/0.1".to_string(), false, Service::AWS, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "exec start \"Ubuntu 19.10 Server\"".to_string(), false);
-        let aws_expected_string = String::from("NORMAN/0.1|false|AWS|REQUEST|200 OK|0|None| |exec start \"Ubuntu 19.10 Server\"|false|NORMAN/END");
+fn option_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, UsageError> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => Err(UsageError::Invalid(format!("{} needs a value", option))),
+    }
+}
 
-        assert_eq!(shell_expected_string, shell_packet.as_string());
-        assert_eq!(aws_expected_string, aws_packet.as_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<UserOptions, UsageError> {
+        UserOptions::new(std::iter::once("norman-client").chain(args.iter().copied()).map(String::from))
     }
 
     #[test]
-    fn error_packets() {
-        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+    fn target_and_command() {
+        let options = parse(&["10.0.0.5", "7878", "ps", "aux", "|", "grep", "norman"]).unwrap();
+
+        assert_eq!(options.target.ip, "10.0.0.5");
+        assert_eq!(options.target.port, 7878);
+        assert_eq!(options.command, vec!["ps", "aux", "|", "grep", "norman"]);
+        assert_eq!(options.shell, None);
+        assert_eq!(options.service, Service::SHELL);
+        assert_eq!(options.action, Action::Run);
+        assert!(options.return_output);
+        assert_eq!(options.timeout, None);
+        assert_eq!(options.format, OutputFormat::Plain);
+        assert_eq!(options.key, None);
+        assert_eq!(options.auth, None);
+        assert_eq!(options.tls, None);
+    }
 
-        sentry::integrations::panic::register_panic_handler();
+    #[test]
+    fn options_before_and_after_target() {
+        let options = parse(&["-s", "aws", "10.0.0.5", "-n", "7878", "--timeout", "2.5", "-f", "verbose", "list"]).unwrap();
 
-        let malformed_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:699}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let malformed_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|505 MALFORMED|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
-        let err_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::RETURN, Status::ERROR{code:500}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let err_expected_string = String::from("NORMAN/0.1|true|SHELL|RETURN|500 ERR|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        assert_eq!(options.service, Service::AWS);
+        assert!(!options.return_output);
+        assert_eq!(options.timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(options.format, OutputFormat::Verbose);
+        assert_eq!(options.command, vec!["list"]);
 
-        assert_eq!(malformed_expected_string, malformed_packet.as_string());
-        assert_eq!(err_expected_string, err_packet.as_string());
+        assert_eq!(parse(&["-s", "backup", "localhost", "7878", "run"]).unwrap().service, Service::new("BACKUP").unwrap());
     }
 
     #[test]
-    fn valid_string_conversion() {
-        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+    fn capabilities_subcommand() {
+        let options = parse(&["-t", "5", "capabilities", "10.0.0.5", "7878", "-f", "raw"]).unwrap();
+        assert_eq!(options.action, Action::Capabilities);
+        assert_eq!(options.target.ip, "10.0.0.5");
+        assert_eq!(options.format, OutputFormat::Raw);
+        assert!(options.command.is_empty());
+
+        assert_eq!(parse(&["10.0.0.5", "7878", "capabilities"]).unwrap().action, Action::Run);
+        assert!(matches!(parse(&["capabilities", "10.0.0.5", "7878", "ls"]), Err(UsageError::Invalid(_))));
+    }
 
-        sentry::integrations::panic::register_panic_handler();
+    #[test]
+    fn ping_subcommand() {
+        let options = parse(&["ping", "10.0.0.5", "7878", "--timeout", "1"]).unwrap();
+        assert_eq!(options.action, Action::Ping);
+        assert_eq!(options.target.port, 7878);
+        assert_eq!(options.timeout, Some(Duration::from_secs(1)));
+
+        assert!(matches!(parse(&["ping", "10.0.0.5", "7878", "uptime"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["ping", "capabilities", "10.0.0.5", "7878"]), Err(UsageError::Invalid(_))));
+    }
 
-        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
-        let aws_packet = NormanPacket::new("NORMAN/0.1".to_string(), false, Service::AWS, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "exec start \"Ubuntu 19.10 Server\"".to_string(), false);
-        let aws_expected_string = String::from("NORMAN/0.1|false|AWS|REQUEST|200 OK|0|None| |exec start \"Ubuntu 19.10 Server\"|false|NORMAN/END");
+    #[test]
+    fn shell_option() {
+        let options = parse(&["--shell", "bash", "localhost", "7878", "ls *.log | wc -l"]).unwrap();
+        assert_eq!(options.shell, Some(String::from("bash")));
+        assert_eq!(options.command, vec!["ls *.log | wc -l"]);
 
-        assert_eq!(NormanPacket::from_string(shell_expected_string), shell_packet);
-        assert_eq!(NormanPacket::from_string(aws_expected_string), aws_packet);
+        assert!(matches!(parse(&["-S", "fish", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["-S", "sh", "-s", "docker", "localhost", "7878", "ps"]), Err(UsageError::Invalid(_))));
     }
 
     #[test]
-    fn error_string_conversion() {
-        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+    fn tls_options() {
+        let options = parse(&["--tls-ca", "ca.pem", "--tls-cert", "me.pem", "--tls-key", "me.key", "--tls-name", "norman.internal", "10.0.0.5", "7878", "uptime"]).unwrap();
+        assert_eq!(options.tls, Some(TlsOptions {
+            ca: String::from("ca.pem"),
+            identity: Some((String::from("me.pem"), String::from("me.key"))),
+            server_name: Some(String::from("norman.internal")),
+        }));
+
+        assert!(matches!(parse(&["--tls-ca", "ca.pem", "--tls-cert", "me.pem", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--tls-cert", "me.pem", "--tls-key", "me.key", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+    }
 
-        sentry::integrations::panic::register_panic_handler();
+    #[test]
+    fn command_arguments_are_not_options() {
+        let options = parse(&["localhost", "7878", "ls", "-la", "--color"]).unwrap();
+        assert_eq!(options.command, vec!["ls", "-la", "--color"]);
 
-        let malformed_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::MALFORMED{code:505}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let malformed_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|505 MALFORMED|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
-        let err_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::RETURN, Status::ERROR{code:500}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let err_expected_string = String::from("NORMAN/0.1|true|SHELL|RETURN|500 ERR|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        let options = parse(&["localhost", "7878", "--", "-weird-program"]).unwrap();
+        assert_eq!(options.command, vec!["-weird-program"]);
 
-        assert_eq!(NormanPacket::from_string(malformed_expected_string), malformed_packet);
-        assert_eq!(NormanPacket::from_string(err_expected_string), err_packet);
+        let options = parse(&["localhost", "7878"]).unwrap();
+        assert!(options.command.is_empty());
     }
 
     #[test]
-    fn recursive_conversion() {
-        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
-
-        sentry::integrations::panic::register_panic_handler();
+    fn uids_are_unique() {
+        let uids: Vec<i32> = (0..100).map(|_| next_uid()).collect();
 
-        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
-        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        assert!(uids.iter().all(|uid| *uid > 0));
+        assert!(uids.windows(2).all(|pair| pair[0] != pair[1]));
+    }
 
-        assert_eq!(NormanPacket::from_string(shell_packet.clone().as_string()), shell_packet.clone());
-        assert_eq!(NormanPacket::from_string(shell_expected_string.clone()).as_string(), shell_expected_string.clone());
+    #[test]
+    fn usage_errors() {
+        assert_eq!(parse(&["--help"]).err(), Some(UsageError::HelpRequested));
+        assert_eq!(parse(&[]).err(), Some(UsageError::Invalid(String::from("No ip provided"))));
+        assert_eq!(parse(&["localhost"]).err(), Some(UsageError::Invalid(String::from("No port provided"))));
+        assert!(matches!(parse(&["localhost", "http"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["-s", "F/TP", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["-t", "soon", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["localhost", "7878", "-t"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--verbose", "localhost", "7878"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["-k", "/nonexistent/norman.key", "localhost", "7878"]), Err(UsageError::Invalid(_))));
     }
 
     #[test]
-    fn random_packet_string_conversion(){
-        let packet1 = gen_random_packet();
-        let packet1_string = packet1.clone().as_string();
-        let packet2 = gen_random_packet();
-        let packet2_string = packet2.clone().as_string();
-        let packet3 = gen_random_packet();
-        let packet3_string = packet3.clone().as_string();
-
-        assert_eq!(packet1.as_string(), packet1_string);
-        assert_eq!(packet2.as_string(), packet2_string);
-        assert_eq!(packet3.as_string(), packet3_string);
+    fn key_file() {
+        let key = PresharedKey::generate();
+        let path = std::env::temp_dir().join(format!("norman-client-test-{}.key", std::process::id()));
+        fs::write(&path, key.to_hex()).unwrap();
+
+        let options = parse(&["--key-file", path.to_str().unwrap(), "localhost", "7878", "uptime"]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(options.unwrap().key, Some(key));
     }
 
     #[test]
-    fn random_packet_from_string(){
-        let packet1 = gen_random_packet();
-        let packet1_string = packet1.clone().as_string();
-        let packet2 = gen_random_packet();
-        let packet2_string = packet2.clone().as_string();
-        let packet3 = gen_random_packet();
-        let packet3_string = packet3.clone().as_string();
-
-        assert_eq!(NormanPacket::from_string(packet1_string), packet1);
-        assert_eq!(NormanPacket::from_string(packet2_string), packet2);
-        assert_eq!(NormanPacket::from_string(packet3_string), packet3);
+    fn client_credentials() {
+        let path = std::env::temp_dir().join(format!("norman-client-test-{}.client-key", std::process::id()));
+        fs::write(&path, "ab".repeat(32)).unwrap();
+        let path = path.to_str().unwrap();
+
+        let options = parse(&["-u", "alice", "--client-key-file", path, "localhost", "7878", "uptime"]);
+        let missing_key = parse(&["-u", "alice", "localhost", "7878"]);
+        let bad_id = parse(&["-u", "al|ice", "--client-key-file", path, "localhost", "7878"]);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(options.unwrap().auth, Some((String::from("alice"), ClientKey::from_hex(&"ab".repeat(32)).unwrap())));
+        assert!(matches!(missing_key, Err(UsageError::Invalid(_))));
+        assert!(matches!(bad_id, Err(UsageError::Invalid(_))));
     }
-}
\ No newline at end of file
+}
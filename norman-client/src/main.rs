@@ -1,44 +1,154 @@
-use std::net::{TcpStream, TcpListener};
 use norman_client::*;
-use std::io::prelude::*;
+use norman_protocol::*;
+use std::io::{self, prelude::*};
+use std::time::{Duration, Instant};
+use std::{env, process};
 
-//use norman_client::UserOptions;
 fn main() {
-    // let user_args = UserOptions::new(env::args()).unwrap_or_else(|err| {
-    //     eprintln!("Problem parsing arguments: {}", err);
-    //     process::exit(1);
-    // });
+    let user_args = UserOptions::new(env::args()).unwrap_or_else(|err| match err {
+        UsageError::HelpRequested => {
+            println!("{}", USAGE);
+            process::exit(0);
+        },
+        UsageError::Invalid(message) => {
+            eprintln!("Problem parsing arguments: {}\n\n{}", message, USAGE);
+            process::exit(1);
+        },
+    });
     let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
 
     sentry::integrations::panic::register_panic_handler();
 
-
-    let mut stream = match TcpStream::connect("127.0.0.1:7878") {
-        Ok(tcp_stream) => tcp_stream,
-        Err(error) => {
-            panic!("Issue connecting to remote host: {:?}", error)
+    //Words from the command line go over as an argv unless a shell is to interpret them
+    let (line, argv) = match (user_args.action, user_args.command.is_empty(), &user_args.shell) {
+        (Action::Capabilities, _, _) | (Action::Ping, _, _) => (String::new(), Vec::new()),
+        (Action::Run, false, None) => (String::new(), user_args.command.clone()),
+        (Action::Run, false, Some(_)) => (user_args.command.join(" "), Vec::new()),
+        (Action::Run, true, _) => {
+            let mut command = String::new();
+            if let Err(error) = io::stdin().read_to_string(&mut command) {
+                fail(&format!("Problem reading command from stdin: {}", error));
+            }
+            (command.trim_end_matches(&['\r', '\n'][..]).to_string(), Vec::new())
         },
     };
+    if user_args.action == Action::Run && line.trim().is_empty() && argv.is_empty() {
+        fail("No command provided");
+    }
 
-    let listener = match TcpListener::bind("127.0.0.1:7575") {
-        Ok(listener) => listener,
-        Err(error) => {
-            panic!("Error binding listener: {:?}", error)
+    let stream = tcp_connect((user_args.target.ip.as_str(), user_args.target.port), user_args.timeout).unwrap_or_else(|error| {
+        fail(&format!("Issue connecting to {}:{}: {}", user_args.target.ip, user_args.target.port, error))
+    });
+    let connection = match &user_args.tls {
+        Some(tls) => {
+            let config = tls.client_config().unwrap_or_else(|error| fail(&error));
+            let server_name = tls.server_name.as_ref().unwrap_or(&user_args.target.ip);
+            Connection::tls(stream, config, server_name).unwrap_or_else(|error| fail(&format!("TLS handshake failed: {}", error)))
         },
+        None => Connection::Plain(stream),
     };
 
-    let packet = NormanPacket::new(String::from("NORMAN/0.1"), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code: 200}, String::from("None"), String::from("echo \"Hello World\""), false);
+    let mut client = NormanClient::new(connection);
+    if let Some(key) = &user_args.key {
+        client = client.with_key(key.clone());
+    }
+    if let Some((client_id, client_key)) = &user_args.auth {
+        client = client.with_auth(client_id.clone(), client_key.clone());
+    }
 
-    stream.write(packet.as_string().as_bytes()).unwrap();
+    let command = Command {
+        service: user_args.service.clone(),
+        line,
+        argv,
+        shell: user_args.shell.clone(),
+        return_output: user_args.return_output,
+    };
 
-    for stream in listener.incoming().take(1) {
-        let mut stream = stream.unwrap();
+    match (user_args.action, user_args.format) {
+        (Action::Run, OutputFormat::Plain) => match client.execute(command) {
+            Ok(output) => {
+                match output.exit_code {
+                    //The command ran, so pass its output through untouched
+                    Some(_) => {
+                        print!("{}", output.stdout);
+                        eprint!("{}", output.stderr);
+                    },
+                    None => println!("{}", output.stdout),
+                }
+                //Exit the way the remote command did so scripts can check it
+                if let Some(exit_code) = output.exit_code.filter(|&exit_code| exit_code != 0) {
+                    process::exit(exit_code);
+                }
+            },
+            Err(error) => fail(&error.to_string()),
+        },
+        (Action::Capabilities, OutputFormat::Plain) => match client.capabilities() {
+            Ok(capabilities) => println!("{}", capabilities),
+            Err(error) => fail(&error.to_string()),
+        },
+        (Action::Ping, OutputFormat::Plain) => match client.ping() {
+            Ok(Ping{round_trip, health}) => {
+                println!("Reply from {}:{} in {:.2} ms", user_args.target.ip, user_args.target.port, round_trip.as_secs_f64() * 1000.0);
+                println!("Up for {}", format_uptime(health.uptime.as_secs()));
+                println!("{} of {} workers busy, {} requests queued", health.busy_workers, health.workers, health.queued);
+                println!("{} speaking {}", health.software, health.version);
+            },
+            Err(error) => fail(&error.to_string()),
+        },
+        (action, format) => {
+            let request = match action {
+                Action::Run => command.packet(),
+                Action::Capabilities => capabilities_request(),
+                Action::Ping => ping_request(),
+            };
+
+            let sent_at = Instant::now();
+            let return_packet = client.request(request).unwrap_or_else(|error| fail(&error.to_string()));
+            let round_trip = sent_at.elapsed();
 
-        let mut buffer = [0; 512];
-        stream.read(&mut buffer).unwrap();
+            match format {
+                OutputFormat::Raw => println!("{}", return_packet.as_string()),
+                _ => print_verbose(&return_packet, round_trip),
+            }
+
+            match return_packet.data.exit_code {
+                Some(exit_code) if exit_code != 0 => process::exit(exit_code),
+                _ if !return_packet.meta.status.is_success() => process::exit(1),
+                _ => {},
+            }
+        },
+    }
+}
 
-        let return_packet = NormanPacket::from_string(String::from_utf8(buffer.to_vec()).unwrap());
+/// Everything about a response, for reading by people rather than scripts.
+fn print_verbose(return_packet: &NormanPacket, round_trip: Duration) {
+    println!("Status: {}", return_packet.meta.status);
+    println!("Type: {:?}", return_packet.meta.req_type);
+    println!("Uid: {}", return_packet.meta.uid);
+    println!("Round trip: {:.2} ms", round_trip.as_secs_f64() * 1000.0);
+    if let Some(exit_code) = return_packet.data.exit_code {
+        println!("Exit code: {}", exit_code);
+    }
+    println!();
+    println!("{}", return_packet.data.data);
+    if !return_packet.data.stderr.is_empty() {
+        println!("Stderr:");
+        println!("{}", return_packet.data.stderr);
+    }
+}
 
-        println!("{}", return_packet.data.data);
+/// Seconds as days, hours, minutes and seconds, leaving out leading zero units.
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", minutes, secs),
+        (0, _, _) => format!("{}h {}m {}s", hours, minutes, secs),
+        _ => format!("{}d {}h {}m {}s", days, hours, minutes, secs),
     }
-}
\ No newline at end of file
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,219 @@
+//! Sending many requests over one connection without waiting for each reply.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use norman_protocol::{ClientKey, NormanPacket, PacketReader, PresharedKey, Reassembler, ENCRYPTION_NONE};
+
+use crate::{next_uid, ClientError};
+
+/// The most requests `run_all` leaves unanswered at once unless told otherwise.
+pub const DEFAULT_WINDOW: usize = 64;
+
+/// A connection that requests are sent down without waiting for replies.
+///
+/// Every request is given a fresh uid as it is sent and its reply is matched
+/// back to it by that uid. The server runs requests side by side and answers
+/// them in whatever order they finish.
+pub struct Pipeline<S> {
+    reader: PacketReader<S>,
+    key: Option<PresharedKey>,
+    auth: Option<(String, ClientKey)>,
+    /// Requests still waiting for a reply, with whatever fragments of it have arrived.
+    pending: BTreeMap<i32, Reassembler>,
+}
+
+impl<S: Read + Write> Pipeline<S> {
+    pub fn new(stream: S) -> Pipeline<S> {
+        Pipeline {
+            reader: PacketReader::new(stream),
+            key: None,
+            auth: None,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Encrypt requests and decrypt replies with `key`.
+    pub fn with_key(mut self, key: PresharedKey) -> Pipeline<S> {
+        self.key = Some(key);
+        self
+    }
+
+    /// Sign requests as `client_id`.
+    pub fn with_auth(mut self, client_id: String, key: ClientKey) -> Pipeline<S> {
+        self.auth = Some((client_id, key));
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+
+    /// The number of requests sent that haven't had their reply yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Send a request without waiting for its reply, giving back the uid the reply will carry.
+    pub fn send(&mut self, packet: NormanPacket) -> Result<i32, ClientError> {
+        let uid = next_uid();
+        let mut packet = packet.with_uid(uid);
+        if let Some(key) = &self.key {
+            packet.encrypt(key);
+        }
+        //Sign last so the signature covers the ciphertext
+        if let Some((client_id, client_key)) = &self.auth {
+            packet.sign(client_id, client_key);
+        }
+
+        let stream = self.reader.get_mut();
+        stream.write_all(&packet.to_bytes())?;
+        stream.flush()?;
+
+        self.pending.insert(uid, Reassembler::new());
+        Ok(uid)
+    }
+
+    /// Wait for the next whole reply to any request still waiting for one.
+    ///
+    /// A reply with uid 0 is to a request the server couldn't read, so it
+    /// can't say which request it answers. It is handed back as it is.
+    /// Packets for requests that aren't waiting are skipped. With a key, a
+    /// reply sent in the clear could have come from anyone, so it is an error.
+    pub fn receive(&mut self) -> Result<NormanPacket, ClientError> {
+        loop {
+            let mut packet = self.reader.read_packet()?.ok_or(ClientError::Closed)?;
+            let uid = packet.meta.uid;
+            if uid != 0 && !self.pending.contains_key(&uid) {
+                continue;
+            }
+            if self.key.is_some() && packet.encryption.encoding_type == ENCRYPTION_NONE {
+                self.pending.remove(&uid);
+                return Err(ClientError::Unencrypted(packet.meta.status, packet.data.data));
+            }
+            packet.decrypt(self.key.as_ref()).map_err(ClientError::Decrypt)?;
+
+            let reassembler = match self.pending.get_mut(&uid) {
+                Some(reassembler) => reassembler,
+                None => return Ok(packet),
+            };
+            if let Some(reply) = reassembler.push(packet).map_err(ClientError::Reassembly)? {
+                self.pending.remove(&uid);
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Wait for the reply to the request sent as `uid`, dropping replies to any others.
+    ///
+    /// If waiting fails the request is given up on, so a reply that turns up
+    /// late is skipped rather than taken for the answer to a later request.
+    pub fn receive_for(&mut self, uid: i32) -> Result<NormanPacket, ClientError> {
+        loop {
+            match self.receive() {
+                Ok(reply) if reply.meta.uid == uid || reply.meta.uid == 0 => return Ok(reply),
+                Ok(_) => continue,
+                Err(error) => {
+                    self.pending.remove(&uid);
+                    return Err(error);
+                },
+            }
+        }
+    }
+
+    /// Send every request and wait for all of their replies, which come back in the order of the requests.
+    ///
+    /// No more than `window` requests are left unanswered at a time, so the
+    /// server is never left writing replies that nobody is reading.
+    pub fn run_all(&mut self, requests: Vec<NormanPacket>, window: usize) -> Result<Vec<NormanPacket>, ClientError> {
+        let window = window.max(1);
+        let mut uids = Vec::with_capacity(requests.len());
+        let mut replies = BTreeMap::new();
+
+        let mut requests = requests.into_iter();
+        while replies.len() < uids.len() || requests.len() > 0 {
+            match requests.len() > 0 && self.pending.len() < window {
+                true => uids.push(self.send(requests.next().unwrap())?),
+                false => {
+                    let reply = self.receive()?;
+                    if reply.meta.uid == 0 {
+                        return Err(ClientError::Rejected(reply.data.data));
+                    }
+                    replies.insert(reply.meta.uid, reply);
+                },
+            }
+        }
+
+        Ok(uids.iter().filter_map(|uid| replies.remove(uid)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use norman_protocol::{RequestType, Service, Status, WireFormat, PROTOCOL_VERSION};
+
+    fn request(command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::REQUEST, Status::OK, String::from("None"), command.to_string(), false)
+    }
+
+    /// A server that reads `count` requests, then answers them last to first,
+    /// with a stray reply first and the answer to the first request in fragments.
+    fn mock_server(count: usize) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = PacketReader::new(stream);
+            let requests: Vec<NormanPacket> = (0..count).map(|_| reader.read_packet().unwrap().unwrap()).collect();
+            let stream = reader.get_mut();
+
+            let stray = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::OK, String::from("None"), String::from("not yours"), false).with_uid(-5);
+            stream.write_all(&stray.encode(WireFormat::Framed)).unwrap();
+            for request in requests.iter().rev() {
+                let reply = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::OK, String::from("None"), format!("ran {}", request.data.data), false).with_uid(request.meta.uid);
+                for fragment in reply.split(4) {
+                    stream.write_all(&fragment.encode(WireFormat::Framed)).unwrap();
+                }
+            }
+        });
+
+        TcpStream::connect(address).unwrap()
+    }
+
+    #[test]
+    fn replies_are_matched_to_requests() {
+        let mut pipeline = Pipeline::new(mock_server(3));
+        let commands = ["uptime", "df -h", "whoami"];
+
+        let replies = pipeline.run_all(commands.iter().map(|command| request(command)).collect(), DEFAULT_WINDOW).unwrap();
+
+        assert_eq!(replies.iter().map(|reply| reply.data.data.as_str()).collect::<Vec<&str>>(), vec!["ran uptime", "ran df -h", "ran whoami"]);
+        assert_eq!(pipeline.pending(), 0);
+    }
+
+    #[test]
+    fn replies_arrive_in_completion_order() {
+        let mut pipeline = Pipeline::new(mock_server(2));
+        let first = pipeline.send(request("first")).unwrap();
+        let second = pipeline.send(request("second")).unwrap();
+        assert_eq!(pipeline.pending(), 2);
+
+        assert_eq!(pipeline.receive().unwrap().meta.uid, second);
+        assert_eq!(pipeline.receive().unwrap().meta.uid, first);
+        assert!(matches!(pipeline.receive(), Err(ClientError::Closed)));
+    }
+
+    #[test]
+    fn replies_in_the_clear_are_refused_with_a_key() {
+        let mut pipeline = Pipeline::new(mock_server(1)).with_key(PresharedKey::generate());
+        pipeline.send(request("uptime")).unwrap();
+
+        assert!(matches!(pipeline.receive(), Err(ClientError::Unencrypted(Status::OK, _))));
+        assert_eq!(pipeline.pending(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use norman_protocol::{load_certs, load_key};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// Where to find the certificates for talking to the server over TLS.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TlsOptions {
+    /// PEM file holding the CA the server's certificate must be signed by.
+    pub ca: String,
+    /// PEM certificate and key identifying this machine to servers that check client certificates.
+    pub identity: Option<(String, String)>,
+    /// Name to check the server's certificate against, when it isn't the address connected to.
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    /// Load the certificates and build the rustls config for connecting.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, String> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca)? {
+            roots.add(cert).map_err(|error| format!("Problem using CA {}: {}", self.ca, error))?;
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+
+        let config = match &self.identity {
+            Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?).map_err(|error| format!("Problem using certificate {}: {}", cert, error))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// A connection to the server, over plain TCP or TLS.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Start TLS over a connected stream, finishing the handshake so certificate problems show up straight away.
+    pub fn tls(mut stream: TcpStream, config: Arc<ClientConfig>, server_name: &str) -> io::Result<Connection> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+        let mut connection = ClientConnection::new(config, server_name).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        Ok(Connection::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{ServerConfig, ServerConnection};
+
+    #[test]
+    fn mutual_tls_round_trip() {
+        let dir = std::env::temp_dir().join(format!("norman-client-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_str().unwrap().to_string();
+
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "norman test CA");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        fs::write(path("ca.pem"), ca.pem()).unwrap();
+
+        for (name, usage) in &[("server", ExtendedKeyUsagePurpose::ServerAuth), ("client", ExtendedKeyUsagePurpose::ClientAuth)] {
+            let mut params = CertificateParams::new(vec![String::from("localhost"), String::from("127.0.0.1")]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, *name);
+            params.extended_key_usages = vec![usage.clone()];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(path(&format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(path(&format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add(load_certs(&path("ca.pem")).unwrap().remove(0)).unwrap();
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().unwrap();
+        let server_config = Arc::new(ServerConfig::builder().with_client_cert_verifier(verifier).with_single_cert(load_certs(&path("server.pem")).unwrap(), load_key(&path("server.key")).unwrap()).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = StreamOwned::new(ServerConnection::new(server_config).unwrap(), stream);
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let options = TlsOptions{ca: path("ca.pem"), identity: Some((path("client.pem"), path("client.key"))), server_name: None};
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut connection = Connection::tls(stream, options.client_config().unwrap(), "127.0.0.1").unwrap();
+        connection.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        connection.read_exact(&mut buf).unwrap();
+
+        server.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn missing_ca() {
+        let options = TlsOptions{ca: String::from("/nonexistent/ca.pem"), identity: None, server_name: None};
+        assert!(options.client_config().is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Signing packets so the server knows which client sent them.
+//!
+//! A signed packet carries `<client id>:<timestamp>:<hmac>` in its `key` field.
+//! The HMAC-SHA256 is taken with the client's key over the packet's framed
+//! encoding with `key` set to just `<client id>:<timestamp>`, so every other
+//! field is covered. Sign after encrypting, so the tag covers the ciphertext.
+//! The timestamp, in seconds since the Unix epoch, bounds how long a signed
+//! packet stays good: `verify` turns away any further than `MAX_CLOCK_SKEW`
+//! from now. Within that window a captured packet verifies every time it is
+//! sent, so servers also keep a `ReplayCache` of the signatures they have
+//! accepted and refuse to run the same packet twice. The cache lives in
+//! memory, so a packet captured shortly before a server restarts can be
+//! replayed once more after it until its timestamp expires.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::NormanPacket;
+
+/// How far a signed packet's timestamp may be from the server's clock, in seconds.
+pub const MAX_CLOCK_SKEW: u64 = 300;
+
+/// The shortest client key accepted, in bytes.
+pub const MIN_CLIENT_KEY_LEN: usize = 16;
+
+/// A key shared between the server and one client for signing that client's requests.
+#[derive(PartialEq, Clone)]
+pub struct ClientKey(Vec<u8>);
+
+impl ClientKey {
+    /// Parse a key written as hex, at least 16 bytes long.
+    pub fn from_hex(hex_key: &str) -> Result<ClientKey, AuthError> {
+        match hex::decode(hex_key.trim()) {
+            Ok(bytes) if bytes.len() >= MIN_CLIENT_KEY_LEN => Ok(ClientKey(bytes)),
+            _ => Err(AuthError::BadKey),
+        }
+    }
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientKey(..)")
+    }
+}
+
+/// The ways a packet can fail authentication.
+#[derive(PartialEq, Clone, Debug)]
+pub enum AuthError {
+    /// The packet carries no credential.
+    Unsigned,
+    /// The credential isn't `<client id>:<timestamp>:<hmac>`.
+    Malformed,
+    /// The client isn't one the server knows.
+    UnknownClient(String),
+    /// The HMAC didn't match, so the packet was altered or signed with another key.
+    BadSignature,
+    /// The timestamp is further than `MAX_CLOCK_SKEW` seconds from now.
+    Expired,
+    /// The packet has been accepted before.
+    Replayed,
+    /// A key was not at least 16 bytes written as hex.
+    BadKey,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Unsigned => write!(f, "Packet is not signed"),
+            AuthError::Malformed => write!(f, "Packet credential is malformed"),
+            AuthError::UnknownClient(client_id) => write!(f, "Unknown client \"{}\"", client_id),
+            AuthError::BadSignature => write!(f, "Packet signature does not match"),
+            AuthError::Expired => write!(f, "Packet timestamp is outside the allowed {} seconds", MAX_CLOCK_SKEW),
+            AuthError::Replayed => write!(f, "Packet has already been used"),
+            AuthError::BadKey => write!(f, "Client key must be at least {} bytes written as hex", MIN_CLIENT_KEY_LEN),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+impl NormanPacket {
+    /// Sign the packet as coming from `client_id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `client_id` is empty or contains `:` or `|`.
+    pub fn sign(&mut self, client_id: &str, key: &ClientKey) {
+        self.sign_at(client_id, key, now());
+    }
+
+    fn sign_at(&mut self, client_id: &str, key: &ClientKey, timestamp: u64) {
+        assert!(!client_id.is_empty() && !client_id.contains(&[':', '|'][..]), "client id must be non-empty and free of ':' and '|'");
+
+        self.encryption.key = format!("{}:{}", client_id, timestamp);
+        let tag = hex::encode(self.mac(key).finalize().into_bytes());
+        self.encryption.key = format!("{}:{}:{}", client_id, timestamp, tag);
+    }
+
+    /// The client a signed packet claims to come from. Check it with `verify` before trusting it.
+    pub fn client_id(&self) -> Option<&str> {
+        let mut parts = self.encryption.key.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(client_id), Some(_), Some(_)) if !client_id.is_empty() => Some(client_id),
+            _ => None,
+        }
+    }
+
+    /// Check the packet was signed with `key` recently.
+    ///
+    /// This says nothing about whether the packet has been seen before; see `ReplayCache`.
+    pub fn verify(&self, key: &ClientKey) -> Result<(), AuthError> {
+        let (client_id, timestamp, tag) = self.credential()?;
+
+        let mut unsigned = self.clone();
+        unsigned.encryption.key = format!("{}:{}", client_id, timestamp);
+        unsigned.mac(key).verify_slice(&tag).map_err(|_| AuthError::BadSignature)?;
+
+        let now = now();
+        if timestamp.max(now) - timestamp.min(now) > MAX_CLOCK_SKEW {
+            return Err(AuthError::Expired);
+        }
+        Ok(())
+    }
+
+    /// The client id, timestamp and tag of a signed packet.
+    fn credential(&self) -> Result<(&str, u64, Vec<u8>), AuthError> {
+        let mut parts = self.encryption.key.splitn(3, ':');
+        let (client_id, timestamp, tag) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(client_id), Some(timestamp), Some(tag)) => (client_id, timestamp, tag),
+            _ if self.encryption.key.trim().is_empty() => return Err(AuthError::Unsigned),
+            _ => return Err(AuthError::Malformed),
+        };
+        let timestamp = timestamp.parse().map_err(|_| AuthError::Malformed)?;
+        let tag = hex::decode(tag).map_err(|_| AuthError::Malformed)?;
+
+        Ok((client_id, timestamp, tag))
+    }
+
+    fn mac(&self, key: &ClientKey) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC takes keys of any length");
+        mac.update(&self.to_bytes());
+        mac
+    }
+}
+
+/// The signatures of packets accepted recently, so each signed packet is only run once.
+///
+/// A signature only has to be remembered until its timestamp is more than
+/// `MAX_CLOCK_SKEW` old, as `verify` turns the packet away after that.
+#[derive(Default, Debug)]
+pub struct ReplayCache {
+    /// Timestamp, client id and tag of each signature, oldest first.
+    seen: BTreeSet<(u64, String, Vec<u8>)>,
+}
+
+impl ReplayCache {
+    pub fn new() -> ReplayCache {
+        ReplayCache::default()
+    }
+
+    /// Remember the signature of a packet that has passed `verify`, failing if it was already remembered.
+    pub fn check(&mut self, packet: &NormanPacket) -> Result<(), AuthError> {
+        self.check_at(packet, now())
+    }
+
+    fn check_at(&mut self, packet: &NormanPacket, now: u64) -> Result<(), AuthError> {
+        //Anything this old would fail `verify`, so needn't be remembered
+        while matches!(self.seen.first(), Some((timestamp, ..)) if timestamp + MAX_CLOCK_SKEW < now) {
+            self.seen.pop_first();
+        }
+
+        let (client_id, timestamp, tag) = packet.credential()?;
+        match self.seen.insert((timestamp, client_id.to_string(), tag)) {
+            true => Ok(()),
+            false => Err(AuthError::Replayed),
+        }
+    }
+
+    /// The number of signatures being remembered.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestType, Service, Status, PROTOCOL_VERSION};
+
+    fn request(command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code: 200}, String::from("None"), command.to_string(), false)
+    }
+
+    fn key(byte: &str) -> ClientKey {
+        ClientKey::from_hex(&byte.repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let mut packet = request("uptime");
+        assert_eq!(packet.client_id(), None);
+        assert_eq!(packet.verify(&key("aa")), Err(AuthError::Unsigned));
+
+        packet.sign("alice", &key("aa"));
+        assert_eq!(packet.client_id(), Some("alice"));
+
+        let packet = NormanPacket::decode(&packet.to_bytes()).unwrap();
+        assert_eq!(packet.verify(&key("aa")), Ok(()));
+        assert_eq!(packet.verify(&key("bb")), Err(AuthError::BadSignature));
+
+        let text = NormanPacket::from_string(packet.as_string()).unwrap();
+        assert_eq!(text.verify(&key("aa")), Ok(()));
+    }
+
+    #[test]
+    fn rejects_altered_packets() {
+        let mut packet = request("uptime");
+        packet.sign("alice", &key("aa"));
+
+        let mut altered = packet.clone();
+        altered.data.data = String::from("rm -rf /");
+        assert_eq!(altered.verify(&key("aa")), Err(AuthError::BadSignature));
+
+        let mut impostor = packet.clone();
+        impostor.encryption.key = impostor.encryption.key.replacen("alice", "bob", 1);
+        assert_eq!(impostor.verify(&key("aa")), Err(AuthError::BadSignature));
+
+        let mut garbled = packet;
+        garbled.encryption.key = String::from("alice:soon:abcd");
+        assert_eq!(garbled.verify(&key("aa")), Err(AuthError::Malformed));
+    }
+
+    #[test]
+    fn rejects_old_packets() {
+        let mut packet = request("uptime");
+        packet.sign_at("alice", &key("aa"), now() - MAX_CLOCK_SKEW - 60);
+
+        assert_eq!(packet.verify(&key("aa")), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let mut cache = ReplayCache::new();
+        let mut packet = request("uptime");
+        packet.sign("alice", &key("aa"));
+
+        assert_eq!(cache.check(&packet), Ok(()));
+        assert_eq!(cache.check(&packet), Err(AuthError::Replayed));
+
+        let mut shouted = packet.clone();
+        shouted.encryption.key = shouted.encryption.key.to_uppercase().replacen("ALICE", "alice", 1);
+        assert_eq!(shouted.verify(&key("aa")), Ok(()));
+        assert_eq!(cache.check(&shouted), Err(AuthError::Replayed));
+
+        let mut again = request("uptime").with_uid(2);
+        again.sign("alice", &key("aa"));
+        assert_eq!(cache.check(&again), Ok(()));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn forgets_expired_signatures() {
+        let mut cache = ReplayCache::new();
+        let mut packet = request("uptime");
+        packet.sign_at("alice", &key("aa"), 1000);
+
+        assert_eq!(cache.check_at(&packet, 1000), Ok(()));
+        assert_eq!(cache.check_at(&packet, 1000 + MAX_CLOCK_SKEW), Err(AuthError::Replayed));
+
+        let mut later = request("df");
+        later.sign_at("alice", &key("aa"), 1000 + MAX_CLOCK_SKEW + 1);
+        assert_eq!(cache.check_at(&later, 1000 + MAX_CLOCK_SKEW + 1), Ok(()));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn parse_keys() {
+        assert!(ClientKey::from_hex(&"ab".repeat(MIN_CLIENT_KEY_LEN)).is_ok());
+        assert_eq!(ClientKey::from_hex(&"ab".repeat(MIN_CLIENT_KEY_LEN - 1)), Err(AuthError::BadKey));
+        assert_eq!(ClientKey::from_hex("not hex at all, not hex at all!!"), Err(AuthError::BadKey));
+    }
+}
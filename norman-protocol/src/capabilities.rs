@@ -0,0 +1,105 @@
+//! What a server tells clients about itself when asked with a CAPABILITIES request.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{ParseError, Service};
+
+/// A server's description of itself.
+///
+/// It travels as the data of the response, one `Name: value` line per field,
+/// so it reads the same printed as it does on the wire. Lists are separated
+/// by `, `.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Capabilities {
+    /// The protocol version the server speaks.
+    pub version: String,
+    /// The name the server goes by.
+    pub server: String,
+    /// The server program and its version.
+    pub software: String,
+    /// The services the server accepts requests for.
+    pub services: Vec<Service>,
+    /// The encryption types requests may be sent in.
+    pub encryption: Vec<String>,
+    /// The largest request the server will read, in bytes.
+    pub max_packet_size: usize,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let services: Vec<&str> = self.services.iter().map(Service::name).collect();
+
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Server: {}", self.server)?;
+        writeln!(f, "Software: {}", self.software)?;
+        writeln!(f, "Services: {}", services.join(", "))?;
+        writeln!(f, "Encryption: {}", self.encryption.join(", "))?;
+        write!(f, "Max packet size: {}", self.max_packet_size)
+    }
+}
+
+impl FromStr for Capabilities {
+    type Err = ParseError;
+
+    fn from_str(description: &str) -> Result<Capabilities, ParseError> {
+        let mut fields = fields(description);
+        let mut field = |expected: &'static str| match fields.next() {
+            Some((name, value)) if name == expected => Ok(value.to_string()),
+            _ => Err(ParseError::BadCapabilities(expected)),
+        };
+
+        let version = field("Version")?;
+        let server = field("Server")?;
+        let software = field("Software")?;
+        let services = list(&field("Services")?).map(str::parse).collect::<Result<Vec<Service>, ParseError>>()?;
+        let encryption = list(&field("Encryption")?).map(String::from).collect();
+        let max_packet_size = field("Max packet size")?.parse().map_err(|_| ParseError::BadCapabilities("Max packet size"))?;
+
+        Ok(Capabilities{version, server, software, services, encryption, max_packet_size})
+    }
+}
+
+/// The `Name: value` lines of a description, in order.
+pub(crate) fn fields(description: &str) -> impl Iterator<Item = (&str, &str)> {
+    description.lines().map(|line| match line.split_once(": ") {
+        Some((name, value)) => (name, value),
+        None => (line.trim_end_matches(':'), ""),
+    })
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(", ").filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PROTOCOL_VERSION, SUPPORTED_ENCRYPTION};
+
+    #[test]
+    fn round_trip() {
+        let capabilities = Capabilities {
+            version: String::from(PROTOCOL_VERSION),
+            server: String::from("build-01"),
+            software: String::from("norman-server 0.1.0"),
+            services: vec![Service::AWS, Service::new("BACKUP").unwrap(), Service::SHELL],
+            encryption: SUPPORTED_ENCRYPTION.iter().map(|name| name.to_string()).collect(),
+            max_packet_size: 16777216,
+        };
+        let description = capabilities.to_string();
+
+        assert_eq!(description, "Version: NORMAN/0.1\nServer: build-01\nSoftware: norman-server 0.1.0\nServices: AWS, BACKUP, SHELL\nEncryption: None, CHACHA20-POLY1305\nMax packet size: 16777216");
+        assert_eq!(description.parse(), Ok(capabilities.clone()));
+
+        let nothing_offered = Capabilities{services: Vec::new(), ..capabilities};
+        assert_eq!(nothing_offered.to_string().parse(), Ok(nothing_offered));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!("Version: NORMAN/0.1".parse::<Capabilities>(), Err(ParseError::BadCapabilities("Server")));
+        assert_eq!("Server: build-01".parse::<Capabilities>(), Err(ParseError::BadCapabilities("Version")));
+        assert_eq!("Version: NORMAN/0.1\nServer: a\nSoftware: b\nServices: SHELL\nEncryption: None\nMax packet size: lots".parse::<Capabilities>(), Err(ParseError::BadCapabilities("Max packet size")));
+    }
+}
@@ -0,0 +1,275 @@
+//! Encrypting packet contents with a pre-shared key.
+//!
+//! The packet's `encoding_type` says how its data is protected. With
+//! ChaCha20-Poly1305 the whole packet, encoded as a binary frame, is sealed
+//! under a fresh random nonce. The data field is replaced by the hex of the
+//! nonce followed by the ciphertext and tag, and the other data fields are
+//! cleared, so everything a packet carries fits in the text format too. On
+//! opening, the header, metadata and terminator sent in the clear must match
+//! the sealed ones, so none of them can be changed without it showing, and
+//! sealed data can't be moved to another packet.
+
+use std::error::Error;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::{Data, NormanPacket};
+
+/// `encoding_type` for packets sent in the clear.
+pub const ENCRYPTION_NONE: &str = "None";
+
+/// `encoding_type` for packets sealed with ChaCha20-Poly1305.
+pub const ENCRYPTION_CHACHA20_POLY1305: &str = "CHACHA20-POLY1305";
+
+/// Every `encoding_type` this crate understands.
+pub const SUPPORTED_ENCRYPTION: &[&str] = &[ENCRYPTION_NONE, ENCRYPTION_CHACHA20_POLY1305];
+
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key shared ahead of time by client and server.
+#[derive(PartialEq, Clone)]
+pub struct PresharedKey(Key);
+
+impl PresharedKey {
+    /// Parse a key written as 64 hex digits, such as the output of `openssl rand -hex 32`.
+    pub fn from_hex(hex_key: &str) -> Result<PresharedKey, CryptoError> {
+        match hex::decode(hex_key.trim()) {
+            Ok(bytes) if bytes.len() == 32 => Ok(PresharedKey(*Key::from_slice(&bytes))),
+            _ => Err(CryptoError::BadKey),
+        }
+    }
+
+    /// Make a new random key.
+    pub fn generate() -> PresharedKey {
+        PresharedKey(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PresharedKey(..)")
+    }
+}
+
+/// The ways encrypting or decrypting a packet can fail.
+#[derive(PartialEq, Clone, Debug)]
+pub enum CryptoError {
+    /// The packet declares an `encoding_type` that isn't supported, or no key is available for it.
+    UnsupportedEncryption(String),
+    /// A key was not 64 hex digits.
+    BadKey,
+    /// A sealed field was malformed or its tag didn't match, so it was forged, corrupted or sealed with another key.
+    VerificationFailed,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::UnsupportedEncryption(encoding_type) => write!(f, "Unsupported encryption \"{}\"", encoding_type),
+            CryptoError::BadKey => write!(f, "Key must be 32 bytes written as 64 hex digits"),
+            CryptoError::VerificationFailed => write!(f, "Packet failed decryption; it was tampered with or sealed with a different key"),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+impl NormanPacket {
+    /// Seal the whole packet with `key` into its data field and mark it as encrypted.
+    pub fn encrypt(&mut self, key: &PresharedKey) {
+        let cipher = ChaCha20Poly1305::new(&key.0);
+
+        let mut inner = self.clone();
+        inner.encryption.key = String::new();
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, inner.to_bytes().as_slice()).expect("ChaCha20-Poly1305 can seal any message that fits in memory");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        self.data = Data {
+            data: hex::encode(sealed),
+            stderr: String::new(),
+            exit_code: None,
+            argv: Vec::new(),
+            shell: None,
+        };
+        self.encryption.encoding_type = String::from(ENCRYPTION_CHACHA20_POLY1305);
+    }
+
+    /// Open the packet according to its `encoding_type`.
+    ///
+    /// Packets sent in the clear are left alone. Encrypted packets need a key,
+    /// and fail if the sealed packet doesn't verify or doesn't match the
+    /// fields sent alongside it.
+    pub fn decrypt(&mut self, key: Option<&PresharedKey>) -> Result<(), CryptoError> {
+        match (self.encryption.encoding_type.as_str(), key) {
+            (ENCRYPTION_NONE, _) => Ok(()),
+            (ENCRYPTION_CHACHA20_POLY1305, Some(key)) => {
+                let cipher = ChaCha20Poly1305::new(&key.0);
+                let inner = open(&cipher, &self.data.data)?;
+
+                //Anything outside the data field is only trusted if it was sealed too
+                let matches = inner.header == self.header
+                    && inner.meta == self.meta
+                    && inner.terminator == self.terminator
+                    && inner.encryption.encoding_type == ENCRYPTION_NONE
+                    && self.data.stderr.is_empty()
+                    && self.data.exit_code.is_none()
+                    && self.data.argv.is_empty()
+                    && self.data.shell.is_none();
+                if !matches {
+                    return Err(CryptoError::VerificationFailed);
+                }
+
+                self.data = inner.data;
+                self.encryption.encoding_type = String::from(ENCRYPTION_NONE);
+                Ok(())
+            },
+            (encoding_type, _) => Err(CryptoError::UnsupportedEncryption(encoding_type.to_string())),
+        }
+    }
+}
+
+/// Open a sealed packet, failing if it was tampered with or isn't a packet.
+fn open(cipher: &ChaCha20Poly1305, sealed: &str) -> Result<NormanPacket, CryptoError> {
+    let sealed = hex::decode(sealed).map_err(|_| CryptoError::VerificationFailed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::VerificationFailed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| CryptoError::VerificationFailed)?;
+
+    NormanPacket::from_bytes(&plaintext).map_err(|_| CryptoError::VerificationFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestType, Service, Status, PROTOCOL_VERSION};
+
+    fn request(command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code: 200}, ENCRYPTION_NONE.to_string(), command.to_string(), false)
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let key = PresharedKey::generate();
+        let plain = request("cat /etc/shadow | grep root");
+
+        let mut packet = plain.clone();
+        packet.encrypt(&key);
+        assert_eq!(packet.encryption.encoding_type, ENCRYPTION_CHACHA20_POLY1305);
+        assert!(!packet.data.data.contains("shadow"));
+
+        let mut packet = NormanPacket::from_bytes(&packet.to_bytes()).unwrap();
+        packet.decrypt(Some(&key)).unwrap();
+        assert_eq!(packet, plain);
+    }
+
+    #[test]
+    fn encrypts_argv() {
+        let key = PresharedKey::generate();
+        let mut plain = request("");
+        plain.data.argv = vec![String::from("cat"), String::from("/etc/shadow")];
+
+        let mut packet = plain.clone();
+        packet.encrypt(&key);
+        assert!(packet.data.argv.is_empty());
+        assert!(!packet.to_bytes().windows(6).any(|window| window == b"shadow"));
+
+        let mut injected = packet.clone();
+        injected.data.argv = vec![String::from("reboot")];
+        assert_eq!(injected.decrypt(Some(&key)), Err(CryptoError::VerificationFailed));
+
+        packet.decrypt(Some(&key)).unwrap();
+        assert_eq!(packet, plain);
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_tampering() {
+        let key = PresharedKey::generate();
+        let mut packet = request("uptime");
+        packet.encrypt(&key);
+
+        assert_eq!(packet.clone().decrypt(Some(&PresharedKey::generate())), Err(CryptoError::VerificationFailed));
+
+        let mut tampered = packet.clone();
+        let last = tampered.data.data.pop().unwrap();
+        tampered.data.data.push(if last == '0' { '1' } else { '0' });
+        assert_eq!(tampered.decrypt(Some(&key)), Err(CryptoError::VerificationFailed));
+
+        let mut moved = packet.clone();
+        moved.meta.uid = 99;
+        assert_eq!(moved.decrypt(Some(&key)), Err(CryptoError::VerificationFailed));
+
+        let mut swapped = packet;
+        std::mem::swap(&mut swapped.data.data, &mut swapped.data.stderr);
+        assert_eq!(swapped.decrypt(Some(&key)), Err(CryptoError::VerificationFailed));
+    }
+
+    #[test]
+    fn authenticates_whole_packet() {
+        let key = PresharedKey::generate();
+        let mut plain = request("deploy");
+        plain.meta.req_type = RequestType::RETURN;
+        plain.meta.status = Status::INTERNAL_ERROR;
+        plain.data.stderr = String::from("warning: disk nearly full");
+        plain.data.exit_code = Some(1);
+        plain.data.shell = Some(String::from("sh"));
+
+        let mut packet = plain.clone();
+        packet.encrypt(&key);
+
+        let tampered: Vec<fn(&mut NormanPacket)> = vec![
+            |packet| packet.header.return_output = false,
+            |packet| packet.header.service = Service::DOCKER,
+            |packet| packet.header.version = String::from("NORMAN/9.9"),
+            |packet| packet.meta.req_type = RequestType::ERROR,
+            |packet| packet.meta.status = Status::OK,
+            |packet| packet.terminator.multi_packet = true,
+            |packet| packet.terminator.sequence = 1,
+            |packet| packet.data.exit_code = Some(0),
+            |packet| packet.data.stderr = String::from("all good"),
+        ];
+        for tamper in tampered {
+            let mut tampered = packet.clone();
+            tamper(&mut tampered);
+            assert_eq!(tampered.decrypt(Some(&key)), Err(CryptoError::VerificationFailed));
+        }
+
+        //Only the data field is needed, so sealed packets survive the text format
+        let mut packet: NormanPacket = packet.as_string().parse().unwrap();
+        packet.decrypt(Some(&key)).unwrap();
+        assert_eq!(packet, plain);
+    }
+
+    #[test]
+    fn rejects_unsupported_encryption() {
+        let mut packet = request("uptime");
+        packet.encryption.encoding_type = String::from("ROT13");
+        assert_eq!(packet.decrypt(Some(&PresharedKey::generate())), Err(CryptoError::UnsupportedEncryption(String::from("ROT13"))));
+
+        let mut packet = request("uptime");
+        packet.encrypt(&PresharedKey::generate());
+        assert_eq!(packet.decrypt(None), Err(CryptoError::UnsupportedEncryption(String::from(ENCRYPTION_CHACHA20_POLY1305))));
+
+        let mut packet = request("uptime");
+        assert_eq!(packet.decrypt(None), Ok(()));
+    }
+
+    #[test]
+    fn parse_keys() {
+        let key = PresharedKey::generate();
+
+        assert_eq!(PresharedKey::from_hex(&key.to_hex()).unwrap().to_hex(), key.to_hex());
+        assert_eq!(PresharedKey::from_hex("abcd").err(), Some(CryptoError::BadKey));
+        assert_eq!(PresharedKey::from_hex(&"zz".repeat(32)).err(), Some(CryptoError::BadKey));
+    }
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The ways a packet can fail to parse.
+#[derive(PartialEq, Clone, Debug)]
+pub enum ParseError {
+    /// The packet did not split into the expected number of `|` separated fields.
+    FieldCount { expected: usize, found: usize },
+    /// The version field was not of the form `NORMAN/<ver>`.
+    BadVersion(String),
+    /// A flag field held something other than `true` or `false`.
+    BadBoolean(String),
+    /// The service name held characters other than ASCII letters, digits, `-`, `_` and `.`.
+    BadService(String),
+    UnknownRequestType(String),
+    UnknownStatus(String),
+    /// The uid field was not a whole number.
+    BadUid(String),
+    /// The packet did not end with the `NORMAN/END` terminator.
+    MissingTerminator,
+    /// A binary frame did not start with the frame magic.
+    BadMagic,
+    UnsupportedFrameVersion(u16),
+    /// A binary frame, or a field inside it, was not the length it declared.
+    FrameLength { expected: usize, found: usize },
+    /// A binary frame did not carry a required field.
+    MissingField(&'static str),
+    /// A field held a value that could not be decoded.
+    BadField(&'static str),
+    /// A text field was not valid UTF-8.
+    BadUtf8(&'static str),
+    /// A description of a server's capabilities was missing this field or held a bad value for it.
+    BadCapabilities(&'static str),
+    /// A server's answer to a ping was missing this field or held a bad value for it.
+    BadHealth(&'static str),
+    /// The packet can't be written in the text format, because this field holds a `|` or only exists in frames.
+    NotText(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::FieldCount { expected, found } => write!(f, "Malformed packet! Expected {} components but found {}", expected, found),
+            ParseError::BadVersion(version) => write!(f, "Bad protocol version \"{}\"", version),
+            ParseError::BadBoolean(flag) => write!(f, "Expected true or false but found \"{}\"", flag),
+            ParseError::BadService(service) => write!(f, "Bad service name \"{}\"", service),
+            ParseError::UnknownRequestType(req_type) => write!(f, "Unknown request type \"{}\"", req_type),
+            ParseError::UnknownStatus(status) => write!(f, "Unknown status \"{}\"", status),
+            ParseError::BadUid(uid) => write!(f, "Bad packet uid \"{}\"", uid),
+            ParseError::MissingTerminator => write!(f, "Packet is missing its terminator"),
+            ParseError::BadMagic => write!(f, "Frame does not start with the norman magic bytes"),
+            ParseError::UnsupportedFrameVersion(version) => write!(f, "Unsupported frame version {}", version),
+            ParseError::FrameLength { expected, found } => write!(f, "Malformed frame! Expected {} bytes but found {}", expected, found),
+            ParseError::MissingField(field) => write!(f, "Frame is missing the {} field", field),
+            ParseError::BadField(field) => write!(f, "Frame has a malformed {} field", field),
+            ParseError::BadUtf8(field) => write!(f, "The {} field is not valid UTF-8", field),
+            ParseError::BadCapabilities(field) => write!(f, "Capabilities have a missing or malformed \"{}\" field", field),
+            ParseError::BadHealth(field) => write!(f, "Ping reply has a missing or malformed \"{}\" field", field),
+            ParseError::NotText(field) => write!(f, "The {} field can't be sent in the NORMAN/0.1 text format; use the framed format", field),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// The ways reading a packet off a stream can fail.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// A whole packet arrived but could not be parsed.
+    Parse(ParseError),
+    /// The packet is longer than the reader is willing to buffer.
+    TooLarge { limit: usize },
+    /// The stream closed partway through a packet.
+    UnexpectedEof,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(error) => write!(f, "Problem reading packet: {}", error),
+            ReadError::Parse(error) => error.fmt(f),
+            ReadError::TooLarge { limit } => write!(f, "Packet is larger than the {} byte limit", limit),
+            ReadError::UnexpectedEof => write!(f, "Connection closed partway through a packet"),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReadError::Io(error) => Some(error),
+            ReadError::Parse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> ReadError {
+        ReadError::Io(error)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(error: ParseError) -> ReadError {
+        ReadError::Parse(error)
+    }
+}
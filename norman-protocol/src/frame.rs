@@ -0,0 +1,434 @@
+//! Length-prefixed binary framing.
+//!
+//! A frame starts with a ten byte header: the magic bytes `NRMN`, a big-endian
+//! `u16` frame version and a big-endian `u32` body length. The body is a run of
+//! fields, each a one byte tag, a big-endian `u32` length and that many bytes of
+//! value. Because every field carries its own length, commands and outputs may
+//! hold any bytes at all, pipes and newlines included. Unknown tags are skipped
+//! so fields can be added later without breaking older readers.
+
+use crate::{Data, Encryption, Header, Metadata, NormanPacket, ParseError, RequestType, Service, Status, Terminator};
+
+/// The bytes every frame starts with.
+pub const FRAME_MAGIC: &[u8; 4] = b"NRMN";
+
+/// The version of the frame layout written by this crate.
+pub const FRAME_VERSION: u16 = 1;
+
+/// The length of the fixed frame header that precedes the body.
+pub const FRAME_HEADER_LEN: usize = 10;
+
+//Field Tags
+const TAG_VERSION: u8 = 1;
+const TAG_RETURN_OUTPUT: u8 = 2;
+const TAG_SERVICE: u8 = 3;
+const TAG_REQ_TYPE: u8 = 4;
+const TAG_STATUS: u8 = 5;
+const TAG_UID: u8 = 6;
+const TAG_ENCODING_TYPE: u8 = 7;
+const TAG_KEY: u8 = 8;
+const TAG_DATA: u8 = 9;
+const TAG_MULTI_PACKET: u8 = 10;
+const TAG_TERM_STRING: u8 = 11;
+const TAG_SEQUENCE: u8 = 12;
+const TAG_STDERR: u8 = 13;
+const TAG_EXIT_CODE: u8 = 14;
+const TAG_ARGV: u8 = 15;
+const TAG_SHELL: u8 = 16;
+const TAG_SERVICE_NAME: u8 = 17;
+
+/// How a packet is laid out on the wire.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum WireFormat {
+    /// The `|` separated text format of NORMAN/0.1, kept for older peers.
+    Text,
+    /// Length-prefixed binary frames.
+    Framed,
+}
+
+impl WireFormat {
+    /// Work out which format a run of bytes is in by looking for the frame magic.
+    pub fn detect(bytes: &[u8]) -> WireFormat {
+        if bytes.starts_with(FRAME_MAGIC) {
+            WireFormat::Framed
+        } else {
+            WireFormat::Text
+        }
+    }
+}
+
+impl NormanPacket {
+    /// Encode the packet as a binary frame.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        //Header
+        put_field(&mut body, TAG_VERSION, self.header.version.as_bytes());
+        put_field(&mut body, TAG_RETURN_OUTPUT, &[self.header.return_output as u8]);
+        //The built-in services keep their one byte codes; anything else is sent by name too
+        let service = &self.header.service;
+        match Service::BUILTIN.iter().position(|builtin| builtin == service) {
+            Some(code) => put_field(&mut body, TAG_SERVICE, &[code as u8]),
+            None => {
+                put_field(&mut body, TAG_SERVICE, &[3]);
+                put_field(&mut body, TAG_SERVICE_NAME, service.name().as_bytes());
+            },
+        }
+
+        //Metadata
+        put_field(&mut body, TAG_REQ_TYPE, &[match &self.meta.req_type {
+            RequestType::REQUEST => 0,
+            RequestType::RETURN => 1,
+            RequestType::TEST => 2,
+            RequestType::ERROR => 3,
+            RequestType::CAPABILITIES => 4,
+        }]);
+        let (kind, code) = match &self.meta.status {
+            Status::FINE{code} => (0, code),
+            Status::ERROR{code} => (1, code),
+            Status::TEST{code} => (2, code),
+            Status::MALFORMED{code} => (3, code),
+        };
+        let mut status = vec![kind];
+        status.extend_from_slice(&code.to_be_bytes());
+        put_field(&mut body, TAG_STATUS, &status);
+        put_field(&mut body, TAG_UID, &self.meta.uid.to_be_bytes());
+
+        //Encryption
+        put_field(&mut body, TAG_ENCODING_TYPE, self.encryption.encoding_type.as_bytes());
+        put_field(&mut body, TAG_KEY, self.encryption.key.as_bytes());
+
+        //Data
+        put_field(&mut body, TAG_DATA, self.data.data.as_bytes());
+        if !self.data.stderr.is_empty() {
+            put_field(&mut body, TAG_STDERR, self.data.stderr.as_bytes());
+        }
+        if let Some(exit_code) = self.data.exit_code {
+            put_field(&mut body, TAG_EXIT_CODE, &exit_code.to_be_bytes());
+        }
+        if !self.data.argv.is_empty() {
+            //Each argument is itself length-prefixed so they can hold any bytes
+            let mut argv = Vec::new();
+            for arg in &self.data.argv {
+                argv.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+                argv.extend_from_slice(arg.as_bytes());
+            }
+            put_field(&mut body, TAG_ARGV, &argv);
+        }
+        if let Some(shell) = &self.data.shell {
+            put_field(&mut body, TAG_SHELL, shell.as_bytes());
+        }
+
+        //Terminator
+        put_field(&mut body, TAG_MULTI_PACKET, &[self.terminator.multi_packet as u8]);
+        put_field(&mut body, TAG_SEQUENCE, &self.terminator.sequence.to_be_bytes());
+        put_field(&mut body, TAG_TERM_STRING, self.terminator.term_string.as_bytes());
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + body.len());
+        frame.extend_from_slice(FRAME_MAGIC);
+        frame.extend_from_slice(&FRAME_VERSION.to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+
+        frame
+    }
+
+    /// Decode a single binary frame. The slice must hold exactly one frame.
+    pub fn from_bytes(bytes: &[u8]) -> Result<NormanPacket, ParseError> {
+        if bytes.len() < FRAME_HEADER_LEN {
+            return Err(ParseError::FrameLength{expected: FRAME_HEADER_LEN, found: bytes.len()});
+        }
+        if !bytes.starts_with(FRAME_MAGIC) {
+            return Err(ParseError::BadMagic);
+        }
+        let frame_version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if frame_version != FRAME_VERSION {
+            return Err(ParseError::UnsupportedFrameVersion(frame_version));
+        }
+        let body_len = u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize;
+        if bytes.len() != FRAME_HEADER_LEN + body_len {
+            return Err(ParseError::FrameLength{expected: FRAME_HEADER_LEN + body_len, found: bytes.len()});
+        }
+
+        let mut version = None;
+        let mut return_output = None;
+        let mut service = None;
+        let mut req_type = None;
+        let mut status = None;
+        let mut uid = None;
+        let mut encoding_type = None;
+        let mut key = None;
+        let mut data = None;
+        let mut multi_packet = None;
+        let mut term_string = None;
+        let mut sequence = 0;
+        let mut stderr = String::new();
+        let mut exit_code = None;
+        let mut argv = Vec::new();
+        let mut shell = None;
+        let mut service_name = None;
+
+        let mut body = &bytes[FRAME_HEADER_LEN..];
+        while !body.is_empty() {
+            if body.len() < 5 {
+                return Err(ParseError::FrameLength{expected: 5, found: body.len()});
+            }
+            let tag = body[0];
+            let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+            if body.len() < 5 + len {
+                return Err(ParseError::FrameLength{expected: 5 + len, found: body.len()});
+            }
+            let value = &body[5..5 + len];
+            body = &body[5 + len..];
+
+            match tag {
+                TAG_VERSION => version = Some(get_string(value, "version")?),
+                TAG_RETURN_OUTPUT => return_output = Some(get_bool(value, "return_output")?),
+                TAG_SERVICE => service = Some(match value {
+                    [0] => Service::SHELL,
+                    [1] => Service::DOCKER,
+                    [2] => Service::AWS,
+                    [3] => Service::UNKNOWN,
+                    _ => return Err(ParseError::BadField("service")),
+                }),
+                TAG_SERVICE_NAME => service_name = Some(Service::new(&get_string(value, "service")?)?),
+                TAG_REQ_TYPE => req_type = Some(match value {
+                    [0] => RequestType::REQUEST,
+                    [1] => RequestType::RETURN,
+                    [2] => RequestType::TEST,
+                    [3] => RequestType::ERROR,
+                    [4] => RequestType::CAPABILITIES,
+                    _ => return Err(ParseError::BadField("req_type")),
+                }),
+                TAG_STATUS => status = Some(match value {
+                    [kind, code @ ..] if code.len() == 4 => {
+                        let code = i32::from_be_bytes([code[0], code[1], code[2], code[3]]);
+                        match kind {
+                            0 => Status::FINE{code},
+                            1 => Status::ERROR{code},
+                            2 => Status::TEST{code},
+                            3 => Status::MALFORMED{code},
+                            _ => return Err(ParseError::BadField("status")),
+                        }
+                    },
+                    _ => return Err(ParseError::BadField("status")),
+                }),
+                TAG_UID => uid = Some(get_i32(value, "uid")?),
+                TAG_ENCODING_TYPE => encoding_type = Some(get_string(value, "encoding_type")?),
+                TAG_KEY => key = Some(get_string(value, "key")?),
+                TAG_DATA => data = Some(get_string(value, "data")?),
+                TAG_MULTI_PACKET => multi_packet = Some(get_bool(value, "multi_packet")?),
+                TAG_TERM_STRING => term_string = Some(get_string(value, "term_string")?),
+                TAG_SEQUENCE => sequence = get_u32(value, "sequence")?,
+                TAG_STDERR => stderr = get_string(value, "stderr")?,
+                TAG_EXIT_CODE => exit_code = Some(get_i32(value, "exit_code")?),
+                TAG_ARGV => argv = get_string_list(value, "argv")?,
+                TAG_SHELL => shell = Some(get_string(value, "shell")?),
+                _ => {},
+            }
+        }
+
+        let version = version.ok_or(ParseError::MissingField("version"))?;
+        if !version.starts_with("NORMAN/") || version.len() == "NORMAN/".len() {
+            return Err(ParseError::BadVersion(version));
+        }
+
+        Ok(NormanPacket {
+            header: Header {
+                version,
+                return_output: return_output.ok_or(ParseError::MissingField("return_output"))?,
+                service: service_name.or(service).ok_or(ParseError::MissingField("service"))?,
+            },
+            meta: Metadata {
+                req_type: req_type.ok_or(ParseError::MissingField("req_type"))?,
+                status: status.ok_or(ParseError::MissingField("status"))?,
+                uid: uid.ok_or(ParseError::MissingField("uid"))?,
+            },
+            encryption: Encryption {
+                encoding_type: encoding_type.ok_or(ParseError::MissingField("encoding_type"))?,
+                key: key.ok_or(ParseError::MissingField("key"))?,
+            },
+            data: Data {
+                data: data.ok_or(ParseError::MissingField("data"))?,
+                stderr,
+                exit_code,
+                argv,
+                shell,
+            },
+            terminator: Terminator {
+                multi_packet: multi_packet.ok_or(ParseError::MissingField("multi_packet"))?,
+                sequence,
+                term_string: term_string.ok_or(ParseError::MissingTerminator)?,
+            },
+        })
+    }
+
+    /// Encode the packet in the given wire format.
+    pub fn encode(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Text => self.as_string().into_bytes(),
+            WireFormat::Framed => self.to_bytes(),
+        }
+    }
+
+    /// Decode a packet in either wire format, falling back to the NORMAN/0.1
+    /// text format when the bytes do not start with the frame magic.
+    pub fn decode(bytes: &[u8]) -> Result<NormanPacket, ParseError> {
+        match WireFormat::detect(bytes) {
+            WireFormat::Framed => NormanPacket::from_bytes(bytes),
+            WireFormat::Text => match std::str::from_utf8(bytes) {
+                Ok(packet_string) => packet_string.parse(),
+                Err(_) => Err(ParseError::BadUtf8("packet")),
+            },
+        }
+    }
+}
+
+fn put_field(body: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    body.push(tag);
+    body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    body.extend_from_slice(value);
+}
+
+fn get_string(value: &[u8], field: &'static str) -> Result<String, ParseError> {
+    String::from_utf8(value.to_vec()).map_err(|_| ParseError::BadUtf8(field))
+}
+
+fn get_string_list(mut value: &[u8], field: &'static str) -> Result<Vec<String>, ParseError> {
+    let mut list = Vec::new();
+    while !value.is_empty() {
+        if value.len() < 4 {
+            return Err(ParseError::BadField(field));
+        }
+        let len = u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as usize;
+        if value.len() < 4 + len {
+            return Err(ParseError::BadField(field));
+        }
+        list.push(get_string(&value[4..4 + len], field)?);
+        value = &value[4 + len..];
+    }
+    Ok(list)
+}
+
+fn get_bool(value: &[u8], field: &'static str) -> Result<bool, ParseError> {
+    match value {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(ParseError::BadField(field)),
+    }
+}
+
+fn get_i32(value: &[u8], field: &'static str) -> Result<i32, ParseError> {
+    match value {
+        [a, b, c, d] => Ok(i32::from_be_bytes([*a, *b, *c, *d])),
+        _ => Err(ParseError::BadField(field)),
+    }
+}
+
+fn get_u32(value: &[u8], field: &'static str) -> Result<u32, ParseError> {
+    match value {
+        [a, b, c, d] => Ok(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => Err(ParseError::BadField(field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    fn shell_packet(command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code: 200}, "None".to_string(), command.to_string(), false)
+    }
+
+    #[test]
+    fn framed_round_trip() {
+        let packet = shell_packet("ps aux | grep norman\necho \"done\" | wc -l");
+        let bytes = packet.to_bytes();
+
+        assert_eq!(WireFormat::detect(&bytes), WireFormat::Framed);
+        assert_eq!(NormanPacket::from_bytes(&bytes).unwrap(), packet);
+        assert_eq!(NormanPacket::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn framed_round_trip_keeps_uid_and_status_code() {
+        let mut packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), false, Service::DOCKER, RequestType::RETURN, Status::FINE{code: 201}, "None".to_string(), String::new(), true);
+        packet.meta.uid = 42;
+
+        assert_eq!(NormanPacket::from_bytes(&packet.to_bytes()).unwrap(), packet);
+    }
+
+    #[test]
+    fn framed_round_trip_keeps_command_results() {
+        let mut packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::ERROR{code: 500}, "None".to_string(), "partial output\n".to_string(), false);
+        packet.data.stderr = String::from("ls: cannot access 'nope': No such file or directory\n");
+        packet.data.exit_code = Some(2);
+
+        assert_eq!(NormanPacket::from_bytes(&packet.to_bytes()).unwrap(), packet);
+    }
+
+    #[test]
+    fn framed_round_trip_keeps_argv_and_shell() {
+        let mut packet = shell_packet("");
+        packet.data.argv = vec![String::from("grep"), String::from("-r"), String::from("a | b"), String::new(), String::from("$HOME")];
+        assert_eq!(NormanPacket::from_bytes(&packet.to_bytes()).unwrap(), packet);
+
+        let mut packet = shell_packet("ls *.log | wc -l");
+        packet.data.shell = Some(String::from("bash"));
+        assert_eq!(NormanPacket::from_bytes(&packet.to_bytes()).unwrap(), packet);
+    }
+
+    #[test]
+    fn framed_round_trip_keeps_service_names() {
+        let mut packet = shell_packet("nightly");
+        packet.header.service = Service::new("backup").unwrap();
+        let bytes = packet.to_bytes();
+
+        assert_eq!(NormanPacket::from_bytes(&bytes).unwrap().header.service.name(), "BACKUP");
+        assert_eq!(NormanPacket::from_bytes(&bytes).unwrap(), packet);
+        //The built-in services are still sent as their codes alone
+        let builtin = shell_packet("uptime").to_bytes();
+        assert!(!builtin.windows(5).any(|window| window == b"SHELL"));
+        assert_eq!(NormanPacket::from_bytes(&builtin).unwrap().header.service, Service::SHELL);
+    }
+
+    #[test]
+    fn text_compatibility_path() {
+        let packet = shell_packet("echo \"Hello from norman\"");
+        let bytes = packet.encode(WireFormat::Text);
+
+        assert_eq!(WireFormat::detect(&bytes), WireFormat::Text);
+        assert_eq!(NormanPacket::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let bytes = shell_packet("uptime").to_bytes();
+
+        assert_eq!(NormanPacket::from_bytes(&bytes[..bytes.len() - 1]), Err(ParseError::FrameLength{expected: bytes.len(), found: bytes.len() - 1}));
+        assert_eq!(NormanPacket::from_bytes(&bytes[..4]), Err(ParseError::FrameLength{expected: FRAME_HEADER_LEN, found: 4}));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(NormanPacket::from_bytes(&bad_magic), Err(ParseError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[5] = 9;
+        assert_eq!(NormanPacket::from_bytes(&bad_version), Err(ParseError::UnsupportedFrameVersion(9)));
+
+        let empty_body = [b'N', b'R', b'M', b'N', 0, 1, 0, 0, 0, 0];
+        assert_eq!(NormanPacket::from_bytes(&empty_body), Err(ParseError::MissingField("version")));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let packet = shell_packet("uptime");
+        let mut bytes = packet.to_bytes();
+        put_field(&mut bytes, 200, b"from the future");
+        let body_len = (bytes.len() - FRAME_HEADER_LEN) as u32;
+        bytes[6..10].copy_from_slice(&body_len.to_be_bytes());
+
+        assert_eq!(NormanPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+}
@@ -0,0 +1,85 @@
+//! What a server reports about itself when pinged with a TEST request.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::capabilities::fields;
+use crate::ParseError;
+
+/// A server's answer to a ping.
+///
+/// Like `Capabilities` it travels as the data of the response, one
+/// `Name: value` line per field.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Health {
+    /// The protocol version the server speaks.
+    pub version: String,
+    /// The server program and its version.
+    pub software: String,
+    /// How long the server has been running, to the second.
+    pub uptime: Duration,
+    /// The number of worker threads running requests.
+    pub workers: usize,
+    /// The number of those threads running a request.
+    pub busy_workers: usize,
+    /// The number of requests waiting for a worker.
+    pub queued: usize,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Software: {}", self.software)?;
+        writeln!(f, "Uptime: {}s", self.uptime.as_secs())?;
+        writeln!(f, "Workers: {}", self.workers)?;
+        writeln!(f, "Busy workers: {}", self.busy_workers)?;
+        write!(f, "Queued: {}", self.queued)
+    }
+}
+
+impl FromStr for Health {
+    type Err = ParseError;
+
+    fn from_str(description: &str) -> Result<Health, ParseError> {
+        let mut fields = fields(description);
+        let mut field = |expected: &'static str| match fields.next() {
+            Some((name, value)) if name == expected => Ok(value.to_string()),
+            _ => Err(ParseError::BadHealth(expected)),
+        };
+        let number = |value: String, name: &'static str| value.parse::<u64>().map_err(|_| ParseError::BadHealth(name));
+
+        let version = field("Version")?;
+        let software = field("Software")?;
+        let uptime = Duration::from_secs(number(field("Uptime")?.trim_end_matches('s').to_string(), "Uptime")?);
+        let workers = number(field("Workers")?, "Workers")? as usize;
+        let busy_workers = number(field("Busy workers")?, "Busy workers")? as usize;
+        let queued = number(field("Queued")?, "Queued")? as usize;
+
+        Ok(Health{version, software, uptime, workers, busy_workers, queued})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    #[test]
+    fn round_trip() {
+        let health = Health {
+            version: String::from(PROTOCOL_VERSION),
+            software: String::from("norman-server 0.1.0"),
+            uptime: Duration::from_secs(93784),
+            workers: 4,
+            busy_workers: 1,
+            queued: 0,
+        };
+        let description = health.to_string();
+
+        assert_eq!(description, "Version: NORMAN/0.1\nSoftware: norman-server 0.1.0\nUptime: 93784s\nWorkers: 4\nBusy workers: 1\nQueued: 0");
+        assert_eq!(description.parse(), Ok(health));
+
+        assert_eq!("Version: NORMAN/0.1\nSoftware: norman-server 0.1.0\nUptime: a while".parse::<Health>(), Err(ParseError::BadHealth("Uptime")));
+    }
+}
@@ -0,0 +1,662 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+mod auth;
+mod capabilities;
+mod crypto;
+mod error;
+mod frame;
+mod health;
+mod multipart;
+mod pem;
+mod reader;
+
+pub use auth::{AuthError, ClientKey, ReplayCache, MAX_CLOCK_SKEW, MIN_CLIENT_KEY_LEN};
+pub use capabilities::Capabilities;
+pub use crypto::{CryptoError, PresharedKey, ENCRYPTION_CHACHA20_POLY1305, ENCRYPTION_NONE, SUPPORTED_ENCRYPTION};
+pub use error::{ParseError, ReadError};
+pub use frame::{WireFormat, FRAME_HEADER_LEN, FRAME_MAGIC, FRAME_VERSION};
+pub use health::Health;
+pub use multipart::{Reassembler, ReassemblyError, DEFAULT_FRAGMENT_SIZE};
+pub use pem::{load_certs, load_key};
+pub use reader::{PacketReader, DEFAULT_MAX_PACKET_SIZE};
+
+//Protocol Constants
+
+/// The protocol version every packet built by this crate declares in its header.
+pub const PROTOCOL_VERSION: &str = "NORMAN/0.1";
+
+/// The string that closes every packet.
+pub const TERMINATOR: &str = "NORMAN/END";
+
+/// The shells a SHELL request may ask for its command to be run through.
+pub const SHELLS: &[&str] = &["sh", "bash", "zsh"];
+
+//Packet Structure
+
+/// The service a request is for.
+///
+/// Services are named rather than enumerated, so a server can offer services
+/// of its own without either crate changing. A name is ASCII letters, digits,
+/// `-`, `_` and `.`; names are case-insensitive and kept in uppercase.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub struct Service(Cow<'static, str>);
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum RequestType{
+    REQUEST,
+    RETURN,
+    /// Pings the server, which answers with `Health` as the data rather than running anything.
+    TEST,
+    ERROR,
+    /// Asks the server to describe itself. Answered with `Capabilities` as the data.
+    CAPABILITIES,
+}
+
+/// How a request went, as an HTTP-like code.
+///
+/// The variant gives the class of the code: TEST for 1xx, FINE for 2xx,
+/// MALFORMED for 505 and ERROR for the other 4xx and 5xx codes. The codes
+/// norman uses have constants below; others in those ranges pass through
+/// the codec untouched.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Status {
+    FINE{code: i32},
+    ERROR{code: i32},
+    TEST{code:i32},
+    MALFORMED{code:i32},
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Header {
+    pub version: String, //Format NORMAN/<ver>
+    pub return_output: bool,
+    pub service: Service,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Metadata {
+    pub req_type: RequestType,
+    pub status: Status,
+    pub uid: i32,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Encryption {
+    pub encoding_type: String,
+    pub key: String,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Data {
+    pub data: String, //The command on requests, its stdout on returns
+    pub stderr: String,
+    pub exit_code: Option<i32>, //None unless a command ran to completion
+    pub argv: Vec<String>, //Program and arguments to run directly instead of data
+    pub shell: Option<String>, //Shell to interpret data with, only for SHELL requests
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Terminator {
+    pub multi_packet: bool, //More packets follow with the same uid
+    pub sequence: u32, //Position of this packet within a multi-packet message
+    pub term_string: String,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct NormanPacket {
+    pub header: Header,
+    pub meta: Metadata,
+    pub encryption: Encryption,
+    pub data: Data,
+    pub terminator: Terminator, //Should be NORMAN/END
+}
+
+impl NormanPacket {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new (ver: String, ret_out: bool, service: Service, req_type: RequestType, status: Status, enc_type: String, data: String, multi_packet: bool) -> NormanPacket {
+        NormanPacket {
+            header: Header {
+                version: ver,
+                return_output: ret_out,
+                service,
+            },
+            meta: Metadata {
+                req_type,
+                status,
+                uid: 0,
+            },
+            encryption: Encryption {
+                encoding_type: enc_type,
+                key: String::from(" "),
+            },
+            data: Data {
+                data,
+                stderr: String::new(),
+                exit_code: None,
+                argv: Vec::new(),
+                shell: None,
+            },
+            terminator: Terminator {
+                multi_packet,
+                sequence: 0,
+                term_string: String::from(TERMINATOR),
+            },
+        }
+    }
+
+    /// The packet with its uid set, so replies can be matched to the request they answer.
+    pub fn with_uid(mut self, uid: i32) -> NormanPacket {
+        self.meta.uid = uid;
+        self
+    }
+
+    pub fn as_string(&self) -> String {
+        let mut packet_string: String = String::new();
+
+        //Concatenate Header
+        packet_string = packet_string + 
+            //Version
+            &self.header.version + "|" +
+            //Return Flag
+            match &self.header.return_output{
+                true => "true",
+                false => "false",
+            } + "|" +
+            //Target Service
+            self.header.service.name() + "|";
+        
+        //Concatenate Metadata
+        packet_string = packet_string + 
+            //Type of packet
+            match &self.meta.req_type {
+                RequestType::REQUEST => "REQUEST",
+                RequestType::RETURN => "RETURN",
+                RequestType::TEST => "TEST",
+                RequestType::ERROR => "ERROR",
+                RequestType::CAPABILITIES => "CAPABILITIES",
+            } + "|" +
+            //Status of packet, with statuses whose variant doesn't fit their code sent as malformed
+            &match Status::from_code(self.meta.status.code()) {
+                Some(ref status) if *status == self.meta.status => status.to_string(),
+                _ => Status::BAD_PACKET.to_string(),
+            } + "|" +
+            //Packet ID
+            &self.meta.uid.to_string()  + "|";
+        //Concatenate Encryption
+            packet_string = packet_string +
+            //Encryption Type
+            &self.encryption.encoding_type + "|" +
+            &self.encryption.key + "|";
+        //Concatenate Data
+        packet_string = packet_string +
+            //Packet Data
+            &self.data.data + "|";
+
+        //Concatenate Terminator
+        packet_string = packet_string +
+            //Concatenated packet flag
+            match &self.terminator.multi_packet{
+                true => "true",
+                false => "false",
+            } + "|" +
+            //Packet terminator
+            &self.terminator.term_string;
+
+        packet_string
+    }
+
+    /// The packet in the NORMAN/0.1 text format, failing when it holds
+    /// something that format can't carry: a `|` in a text field, or any of
+    /// the fields only the framed format has. Unlike `as_string`, whatever
+    /// this gives back parses to the same packet.
+    pub fn to_text(&self) -> Result<String, ParseError> {
+        let text_fields = [
+            ("version", &self.header.version),
+            ("encoding type", &self.encryption.encoding_type),
+            ("key", &self.encryption.key),
+            ("data", &self.data.data),
+            ("terminator", &self.terminator.term_string),
+        ];
+        if let Some((field, _)) = text_fields.iter().find(|(_, value)| value.contains('|')) {
+            return Err(ParseError::NotText(field));
+        }
+
+        let framed_only = [
+            ("stderr", !self.data.stderr.is_empty()),
+            ("exit code", self.data.exit_code.is_some()),
+            ("argv", !self.data.argv.is_empty()),
+            ("shell", self.data.shell.is_some()),
+            ("sequence", self.terminator.sequence != 0),
+        ];
+        if let Some((field, _)) = framed_only.iter().find(|(_, set)| *set) {
+            return Err(ParseError::NotText(field));
+        }
+
+        Ok(self.as_string())
+    }
+
+    pub fn from_string (packet_string: String) -> Result<NormanPacket, ParseError> {
+        packet_string.parse()
+    }
+}
+
+impl FromStr for NormanPacket {
+    type Err = ParseError;
+
+    fn from_str(packet_string: &str) -> Result<NormanPacket, ParseError> {
+        let packet_components: Vec<&str> = packet_string.split('|').collect();
+
+        if packet_components.last() != Some(&TERMINATOR) {
+            return Err(ParseError::MissingTerminator);
+        }
+        if packet_components.len() != 11 {
+            return Err(ParseError::FieldCount{expected: 11, found: packet_components.len()});
+        }
+
+        let version = packet_components[0];
+        if !version.starts_with("NORMAN/") || version.len() == "NORMAN/".len() {
+            return Err(ParseError::BadVersion(version.to_string()));
+        }
+        let return_output = parse_bool(packet_components[1])?;
+        let service = packet_components[2].parse()?;
+
+        let req_type = match packet_components[3] {
+            "REQUEST" => RequestType::REQUEST,
+            "RETURN" => RequestType::RETURN,
+            "TEST" => RequestType::TEST,
+            "ERROR" => RequestType::ERROR,
+            "CAPABILITIES" => RequestType::CAPABILITIES,
+            other => return Err(ParseError::UnknownRequestType(other.to_string())),
+        };
+        let status = packet_components[4].parse()?;
+        let uid = packet_components[5].parse().map_err(|_| ParseError::BadUid(packet_components[5].to_string()))?;
+
+        let encoding_type = packet_components[6].to_string();
+        let key = packet_components[7].to_string();
+
+        let data = packet_components[8].to_string();
+
+        let multi_packet = parse_bool(packet_components[9])?;
+
+        let mut packet = NormanPacket::new(version.to_string(), return_output, service, req_type, status, encoding_type, data, multi_packet).with_uid(uid);
+        packet.encryption.key = key;
+        Ok(packet)
+    }
+}
+
+impl Service {
+    pub const SHELL: Service = Service(Cow::Borrowed("SHELL"));
+    pub const DOCKER: Service = Service(Cow::Borrowed("DOCKER"));
+    pub const AWS: Service = Service(Cow::Borrowed("AWS"));
+    /// Stands in for the service on replies to packets whose service couldn't be read.
+    pub const UNKNOWN: Service = Service(Cow::Borrowed("UNKNOWN"));
+
+    /// The services every norman server knows how to offer.
+    pub const BUILTIN: &'static [Service] = &[Service::SHELL, Service::DOCKER, Service::AWS];
+
+    pub fn new(name: &str) -> Result<Service, ParseError> {
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        match valid {
+            true => Ok(Service(Cow::Owned(name.to_ascii_uppercase()))),
+            false => Err(ParseError::BadService(name.to_string())),
+        }
+    }
+
+    /// The name the service goes by on the wire and in config files.
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Service {
+    type Err = ParseError;
+
+    fn from_str(name: &str) -> Result<Service, ParseError> {
+        Service::new(name)
+    }
+}
+
+impl Status {
+    /// The answer to a ping.
+    pub const PING: Status = Status::TEST{code: 100};
+    pub const OK: Status = Status::FINE{code: 200};
+    /// The request was accepted and is running in the background.
+    pub const ACCEPTED: Status = Status::FINE{code: 202};
+    /// The request made no sense to the service it was for.
+    pub const BAD_REQUEST: Status = Status::ERROR{code: 400};
+    /// The request couldn't be authenticated or decrypted.
+    pub const UNAUTHORIZED: Status = Status::ERROR{code: 401};
+    /// The client's policy doesn't allow the request.
+    pub const FORBIDDEN: Status = Status::ERROR{code: 403};
+    /// The request named something, like a container or instance, that doesn't exist.
+    pub const NOT_FOUND: Status = Status::ERROR{code: 404};
+    /// Something the request was waiting on took too long.
+    pub const TIMEOUT: Status = Status::ERROR{code: 408};
+    /// The request was larger than the server will read, or asked for more work than it will take on.
+    pub const TOO_LARGE: Status = Status::ERROR{code: 413};
+    pub const INTERNAL_ERROR: Status = Status::ERROR{code: 500};
+    /// The server doesn't offer the service or encryption the request asked for.
+    pub const UNSUPPORTED: Status = Status::ERROR{code: 501};
+    /// The packet couldn't be read.
+    pub const BAD_PACKET: Status = Status::MALFORMED{code: 505};
+
+    /// The status a code stands for, or `None` for codes outside the ranges norman uses.
+    pub fn from_code(code: i32) -> Option<Status> {
+        match code {
+            100..=199 => Some(Status::TEST{code}),
+            200..=299 => Some(Status::FINE{code}),
+            505 => Some(Status::MALFORMED{code}),
+            400..=599 => Some(Status::ERROR{code}),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            Status::FINE{code} | Status::ERROR{code} | Status::TEST{code} | Status::MALFORMED{code} => *code,
+        }
+    }
+
+    /// Whether the request went as asked, including pings.
+    pub fn is_success(&self) -> bool {
+        matches!(self, Status::FINE{..} | Status::TEST{..})
+    }
+
+    /// The text that follows the code in the text format.
+    pub fn reason(&self) -> &'static str {
+        match self.code() {
+            202 => "ACCEPTED",
+            400 => "BAD REQUEST",
+            401 => "UNAUTHORIZED",
+            403 => "FORBIDDEN",
+            404 => "NOT FOUND",
+            408 => "TIMEOUT",
+            413 => "TOO LARGE",
+            501 => "UNSUPPORTED",
+            _ => match self {
+                Status::FINE{..} => "OK",
+                Status::ERROR{..} => "ERR",
+                Status::TEST{..} => "TEST",
+                Status::MALFORMED{..} => "MALFORMED",
+            },
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// Reads `<code> <reason>`, where the reason has to be the one `reason` gives for the code.
+impl FromStr for Status {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Status, ParseError> {
+        let status = text.split_once(' ').and_then(|(code, reason)| {
+            let status = Status::from_code(code.parse().ok()?)?;
+            match status.reason() == reason {
+                true => Some(status),
+                false => None,
+            }
+        });
+        status.ok_or_else(|| ParseError::UnknownStatus(text.to_string()))
+    }
+}
+
+impl TryFrom<&str> for NormanPacket {
+    type Error = ParseError;
+
+    fn try_from(packet_string: &str) -> Result<NormanPacket, ParseError> {
+        packet_string.parse()
+    }
+}
+
+fn parse_bool(flag: &str) -> Result<bool, ParseError> {
+    match flag {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        other => Err(ParseError::BadBoolean(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn gen_random_packet() -> NormanPacket {
+        let version = String::from(PROTOCOL_VERSION);
+        let return_output = match rand::thread_rng().gen_range(0, 2) {
+            1 => true,
+            0 => false,
+            _ => true
+        };
+        let service = match rand::thread_rng().gen_range(0, 4) {
+            0 => Service::SHELL,
+            1 => Service::AWS,
+            2 => Service::DOCKER,
+            _ => Service::new("BACKUP").unwrap(),
+        };
+
+        let req_type = match rand::thread_rng().gen_range(0, 5) {
+            0 => RequestType::REQUEST,
+            1 => RequestType::RETURN,
+            2 => RequestType::TEST,
+            3 => RequestType::CAPABILITIES,
+            _ => RequestType::ERROR,
+        };
+        let status = match rand::thread_rng().gen_range(0, 4) {
+            0 => Status::FINE{code: 200},
+            1 => Status::ERROR{code: 500},
+            2 => Status::TEST{code: 100},
+            _ => Status::MALFORMED{code: 505},
+        };
+
+        let encoding_type = String::from("None");
+
+        let data = String::from("Random Packet Example Data");
+
+        let multi_packet = match rand::thread_rng().gen_range(0, 2) {
+            1 => true,
+            0 => false,
+            _ => false,
+        };
+
+        NormanPacket::new(version, return_output, service, req_type, status, encoding_type, data, multi_packet)
+    }
+
+    #[test]
+    fn valid_packets() {
+        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+
+        sentry::integrations::panic::register_panic_handler();
+
+        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        let aws_packet = NormanPacket::new("NORMAN/0.1".to_string(), false, Service::AWS, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "exec start \"Ubuntu 19.10 Server\"".to_string(), false);
+        let aws_expected_string = String::from("NORMAN/0.1|false|AWS|REQUEST|200 OK|0|None| |exec start \"Ubuntu 19.10 Server\"|false|NORMAN/END");
+
+        assert_eq!(shell_expected_string, shell_packet.as_string());
+        assert_eq!(aws_expected_string, aws_packet.as_string());
+    }
+
+    #[test]
+    fn error_packets() {
+        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+
+        sentry::integrations::panic::register_panic_handler();
+
+        let malformed_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:699}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let malformed_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|505 MALFORMED|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        let err_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::RETURN, Status::ERROR{code:500}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let err_expected_string = String::from("NORMAN/0.1|true|SHELL|RETURN|500 ERR|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+
+        assert_eq!(malformed_expected_string, malformed_packet.as_string());
+        assert_eq!(err_expected_string, err_packet.as_string());
+    }
+
+    #[test]
+    fn valid_string_conversion() {
+        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+
+        sentry::integrations::panic::register_panic_handler();
+
+        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        let aws_packet = NormanPacket::new("NORMAN/0.1".to_string(), false, Service::AWS, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "exec start \"Ubuntu 19.10 Server\"".to_string(), false);
+        let aws_expected_string = String::from("NORMAN/0.1|false|AWS|REQUEST|200 OK|0|None| |exec start \"Ubuntu 19.10 Server\"|false|NORMAN/END");
+
+        assert_eq!(NormanPacket::from_string(shell_expected_string).unwrap(), shell_packet);
+        assert_eq!(NormanPacket::from_string(aws_expected_string).unwrap(), aws_packet);
+    }
+
+    #[test]
+    fn error_string_conversion() {
+        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+
+        sentry::integrations::panic::register_panic_handler();
+
+        let malformed_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::MALFORMED{code:505}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let malformed_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|505 MALFORMED|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+        let err_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::RETURN, Status::ERROR{code:500}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let err_expected_string = String::from("NORMAN/0.1|true|SHELL|RETURN|500 ERR|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+
+        assert_eq!(NormanPacket::from_string(malformed_expected_string).unwrap(), malformed_packet);
+        assert_eq!(NormanPacket::from_string(err_expected_string).unwrap(), err_packet);
+    }
+
+    #[test]
+    fn recursive_conversion() {
+        let _guard = sentry::init("https://3d034496ffe8417f988b81f617ee032c@sentry.io/4616084");
+
+        sentry::integrations::panic::register_panic_handler();
+
+        let shell_packet = NormanPacket::new("NORMAN/0.1".to_string(), true, Service::SHELL, RequestType::REQUEST, Status::FINE{code:200}, "None".to_string(), "echo \"Hello from norman\"".to_string(), false);
+        let shell_expected_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo \"Hello from norman\"|false|NORMAN/END");
+
+        assert_eq!(NormanPacket::from_string(shell_packet.clone().as_string()).unwrap(), shell_packet.clone());
+        assert_eq!(NormanPacket::from_string(shell_expected_string.clone()).unwrap().as_string(), shell_expected_string.clone());
+    }
+
+    #[test]
+    fn random_packet_string_conversion(){
+        let packet1 = gen_random_packet();
+        let packet1_string = packet1.clone().as_string();
+        let packet2 = gen_random_packet();
+        let packet2_string = packet2.clone().as_string();
+        let packet3 = gen_random_packet();
+        let packet3_string = packet3.clone().as_string();
+
+        assert_eq!(packet1.as_string(), packet1_string);
+        assert_eq!(packet2.as_string(), packet2_string);
+        assert_eq!(packet3.as_string(), packet3_string);
+    }
+
+    #[test]
+    fn random_packet_from_string(){
+        let packet1 = gen_random_packet();
+        let packet1_string = packet1.clone().as_string();
+        let packet2 = gen_random_packet();
+        let packet2_string = packet2.clone().as_string();
+        let packet3 = gen_random_packet();
+        let packet3_string = packet3.clone().as_string();
+
+        assert_eq!(NormanPacket::from_string(packet1_string).unwrap(), packet1);
+        assert_eq!(NormanPacket::from_string(packet2_string).unwrap(), packet2);
+        assert_eq!(NormanPacket::from_string(packet3_string).unwrap(), packet3);
+    }
+
+    #[test]
+    fn open_service_names() {
+        let ftp = "NORMAN/0.1|true|FTP|REQUEST|200 OK|0|None| |get notes.txt|false|NORMAN/END";
+        let packet = NormanPacket::try_from(ftp).unwrap();
+
+        assert_eq!(packet.header.service.name(), "FTP");
+        assert_eq!(packet.as_string(), ftp);
+        assert_eq!(Service::new("backup-v2").unwrap().to_string(), "BACKUP-V2");
+        assert_eq!("shell".parse::<Service>(), Ok(Service::SHELL));
+        assert!(Service::new("").is_err());
+        assert!(Service::new("a|b").is_err());
+    }
+
+    #[test]
+    fn malformed_string_conversion() {
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |ps aux | grep x|false|NORMAN/END"), Err(ParseError::FieldCount{expected: 11, found: 12}));
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|0|None| |echo"), Err(ParseError::MissingTerminator));
+        assert_eq!(NormanPacket::try_from(""), Err(ParseError::MissingTerminator));
+        assert_eq!(NormanPacket::try_from("HTTP/1.1|true|SHELL|REQUEST|200 OK|0|None| |echo|false|NORMAN/END"), Err(ParseError::BadVersion("HTTP/1.1".to_string())));
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|yes|SHELL|REQUEST|200 OK|0|None| |echo|false|NORMAN/END"), Err(ParseError::BadBoolean("yes".to_string())));
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|F T P|REQUEST|200 OK|0|None| |echo|false|NORMAN/END"), Err(ParseError::BadService("F T P".to_string())));
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|SHELL|PUSH|200 OK|0|None| |echo|false|NORMAN/END"), Err(ParseError::UnknownRequestType("PUSH".to_string())));
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|SHELL|REQUEST|418 TEAPOT|0|None| |echo|false|NORMAN/END"), Err(ParseError::UnknownStatus("418 TEAPOT".to_string())));
+    }
+
+    #[test]
+    fn text_round_trip_or_refuse() {
+        let mut packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::OK, String::from("None"), String::from("root 1 init\n"), false).with_uid(7);
+        assert_eq!(packet.to_text().unwrap().parse::<NormanPacket>().unwrap(), packet);
+
+        //as_string would give a packet with too many fields
+        packet.data.data = String::from("ps aux | grep x");
+        assert!(packet.as_string().parse::<NormanPacket>().is_err());
+        assert_eq!(packet.to_text(), Err(ParseError::NotText("data")));
+
+        packet.data.data = String::from("out");
+        packet.data.stderr = String::from("warning");
+        assert_eq!(packet.to_text(), Err(ParseError::NotText("stderr")));
+    }
+
+    #[test]
+    fn uid_round_trip() {
+        let packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::REQUEST, Status::OK, String::from("None"), String::from("uptime"), false).with_uid(1234);
+        let packet_string = String::from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|1234|None| |uptime|false|NORMAN/END");
+
+        assert_eq!(packet.as_string(), packet_string);
+        assert_eq!(NormanPacket::from_string(packet_string).unwrap(), packet);
+        assert_eq!(NormanPacket::try_from("NORMAN/0.1|true|SHELL|REQUEST|200 OK|one|None| |uptime|false|NORMAN/END"), Err(ParseError::BadUid("one".to_string())));
+    }
+
+    #[test]
+    fn status_codes_round_trip() {
+        let statuses = [
+            (Status::PING, "100 TEST"),
+            (Status::OK, "200 OK"),
+            (Status::FINE{code: 201}, "201 OK"),
+            (Status::ACCEPTED, "202 ACCEPTED"),
+            (Status::BAD_REQUEST, "400 BAD REQUEST"),
+            (Status::UNAUTHORIZED, "401 UNAUTHORIZED"),
+            (Status::FORBIDDEN, "403 FORBIDDEN"),
+            (Status::NOT_FOUND, "404 NOT FOUND"),
+            (Status::TIMEOUT, "408 TIMEOUT"),
+            (Status::TOO_LARGE, "413 TOO LARGE"),
+            (Status::ERROR{code: 429}, "429 ERR"),
+            (Status::INTERNAL_ERROR, "500 ERR"),
+            (Status::UNSUPPORTED, "501 UNSUPPORTED"),
+            (Status::BAD_PACKET, "505 MALFORMED"),
+        ];
+        for (status, text) in statuses.iter() {
+            let packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, status.clone(), String::from("None"), String::from("out"), false);
+            let packet_string = packet.as_string();
+
+            assert_eq!(packet_string, format!("NORMAN/0.1|true|SHELL|RETURN|{}|0|None| |out|false|NORMAN/END", text));
+            assert_eq!(NormanPacket::from_string(packet_string).unwrap().meta.status, *status);
+        }
+
+        assert_eq!("200".parse::<Status>(), Err(ParseError::UnknownStatus("200".to_string())));
+        assert_eq!("302 FOUND".parse::<Status>(), Err(ParseError::UnknownStatus("302 FOUND".to_string())));
+        assert_eq!("404 ERR".parse::<Status>(), Err(ParseError::UnknownStatus("404 ERR".to_string())));
+        assert!(!Status::NOT_FOUND.is_success());
+        assert!(Status::ACCEPTED.is_success() && Status::PING.is_success());
+    }
+}
@@ -0,0 +1,225 @@
+//! Splitting large messages across several packets and putting them back together.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::NormanPacket;
+
+/// The most data the server puts in a single packet of a multi-packet message.
+pub const DEFAULT_FRAGMENT_SIZE: usize = 64 * 1024;
+
+impl NormanPacket {
+    /// Split the packet into a run of packets each carrying at most
+    /// `max_data_len` bytes of data or stderr.
+    ///
+    /// Every fragment keeps the packet's header and uid and is numbered in
+    /// order from zero. The data is sent first, then the stderr. All but the
+    /// last fragment have `multi_packet` set. A packet that already fits comes
+    /// back as a single fragment.
+    pub fn split(&self, max_data_len: usize) -> Vec<NormanPacket> {
+        if self.data.data.len() + self.data.stderr.len() <= max_data_len {
+            return vec![self.clone()];
+        }
+
+        let mut chunks: Vec<(&str, &str)> = chunk_str(&self.data.data, max_data_len).into_iter().map(|chunk| (chunk, "")).collect();
+        chunks.extend(chunk_str(&self.data.stderr, max_data_len).into_iter().map(|chunk| ("", chunk)));
+
+        let last = chunks.len() - 1;
+        chunks.into_iter().enumerate().map(|(sequence, (data, stderr))| {
+            let mut fragment = self.clone();
+            fragment.data.data = data.to_string();
+            fragment.data.stderr = stderr.to_string();
+            fragment.terminator.multi_packet = sequence != last;
+            fragment.terminator.sequence = sequence as u32;
+            fragment
+        }).collect()
+    }
+}
+
+/// Cut a string into pieces of at most `max_len` bytes without splitting a character.
+fn chunk_str(mut rest: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        let mut end = max_len.min(rest.len());
+        while end > 0 && !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    chunks
+}
+
+/// The ways a multi-packet message can arrive broken.
+#[derive(PartialEq, Clone, Debug)]
+pub enum ReassemblyError {
+    /// A fragment was skipped; `found` arrived when `expected` was due.
+    MissingFragment { expected: u32, found: u32 },
+    /// A fragment that had already been received arrived again.
+    DuplicateFragment { sequence: u32 },
+    /// A packet for another message arrived before this one was finished.
+    UidMismatch { expected: i32, found: i32 },
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReassemblyError::MissingFragment { expected, found } => write!(f, "Missing fragment {} of multi-packet message, got fragment {}", expected, found),
+            ReassemblyError::DuplicateFragment { sequence } => write!(f, "Fragment {} of multi-packet message arrived twice", sequence),
+            ReassemblyError::UidMismatch { expected, found } => write!(f, "Expected the rest of message {} but got a packet for {}", expected, found),
+        }
+    }
+}
+
+impl Error for ReassemblyError {}
+
+/// Collects the fragments of a multi-packet message in order.
+///
+/// Packets are pushed one at a time as they are read. Once the final fragment
+/// arrives the whole message is handed back as one packet whose data and
+/// stderr are every fragment's concatenated. If a fragment is missing or
+/// repeated the partial message is thrown away and an error is returned.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: Option<NormanPacket>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Add the next packet, returning the complete message once it has all arrived.
+    pub fn push(&mut self, packet: NormanPacket) -> Result<Option<NormanPacket>, ReassemblyError> {
+        let mut message = match self.partial.take() {
+            Some(partial) => {
+                let expected = partial.terminator.sequence + 1;
+                if packet.meta.uid != partial.meta.uid {
+                    return Err(ReassemblyError::UidMismatch{expected: partial.meta.uid, found: packet.meta.uid});
+                }
+                if packet.terminator.sequence < expected {
+                    return Err(ReassemblyError::DuplicateFragment{sequence: packet.terminator.sequence});
+                }
+                if packet.terminator.sequence > expected {
+                    return Err(ReassemblyError::MissingFragment{expected, found: packet.terminator.sequence});
+                }
+
+                let mut message = packet;
+                message.data.data = partial.data.data + &message.data.data;
+                message.data.stderr = partial.data.stderr + &message.data.stderr;
+                message
+            },
+            None => {
+                if packet.terminator.sequence != 0 {
+                    return Err(ReassemblyError::MissingFragment{expected: 0, found: packet.terminator.sequence});
+                }
+                packet
+            },
+        };
+
+        if message.terminator.multi_packet {
+            self.partial = Some(message);
+            return Ok(None);
+        }
+
+        message.terminator.sequence = 0;
+        Ok(Some(message))
+    }
+
+    /// Whether part of a message has been received but not the end of it.
+    pub fn is_partial(&self) -> bool {
+        self.partial.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestType, Service, Status, PROTOCOL_VERSION};
+
+    fn return_packet(data: &str) -> NormanPacket {
+        let mut packet = NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::FINE{code: 200}, "None".to_string(), data.to_string(), false);
+        packet.meta.uid = 7;
+        packet
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let packet = return_packet("the quick brown fox jumps over the lazy dog");
+        let fragments = packet.split(10);
+
+        assert_eq!(fragments.len(), 5);
+        assert!(fragments[..4].iter().all(|fragment| fragment.terminator.multi_packet));
+        assert!(!fragments[4].terminator.multi_packet);
+        assert!(fragments.iter().all(|fragment| fragment.meta.uid == 7));
+
+        let mut reassembler = Reassembler::new();
+        let mut message = None;
+        for fragment in fragments {
+            assert!(message.is_none());
+            message = reassembler.push(fragment).unwrap();
+        }
+
+        assert_eq!(message, Some(packet));
+        assert!(!reassembler.is_partial());
+    }
+
+    #[test]
+    fn split_and_reassemble_stderr() {
+        let mut packet = return_packet("some output");
+        packet.data.stderr = String::from("and some errors");
+        packet.data.exit_code = Some(1);
+        let fragments = packet.split(8);
+
+        assert_eq!(fragments.iter().map(|fragment| fragment.data.data.as_str()).collect::<Vec<&str>>(), vec!["some out", "put", "", ""]);
+        assert_eq!(fragments.iter().map(|fragment| fragment.data.stderr.as_str()).collect::<Vec<&str>>(), vec!["", "", "and some", " errors"]);
+
+        let mut reassembler = Reassembler::new();
+        let message = fragments.into_iter().map(|fragment| reassembler.push(fragment).unwrap()).last().unwrap();
+
+        assert_eq!(message, Some(packet));
+    }
+
+    #[test]
+    fn small_packets_are_not_split() {
+        let packet = return_packet("short");
+
+        assert_eq!(packet.split(DEFAULT_FRAGMENT_SIZE), vec![packet.clone()]);
+        assert_eq!(Reassembler::new().push(packet.clone()), Ok(Some(packet)));
+    }
+
+    #[test]
+    fn split_respects_character_boundaries() {
+        let packet = return_packet("héllo wörld");
+        let fragments = packet.split(2);
+
+        assert!(fragments.iter().all(|fragment| fragment.data.data.len() <= 2));
+        assert_eq!(fragments.iter().map(|fragment| fragment.data.data.as_str()).collect::<String>(), "héllo wörld");
+    }
+
+    #[test]
+    fn detects_missing_and_duplicate_fragments() {
+        let fragments = return_packet("0123456789abcdef").split(4);
+
+        let mut reassembler = Reassembler::new();
+        reassembler.push(fragments[0].clone()).unwrap();
+        assert_eq!(reassembler.push(fragments[2].clone()), Err(ReassemblyError::MissingFragment{expected: 1, found: 2}));
+
+        let mut reassembler = Reassembler::new();
+        reassembler.push(fragments[0].clone()).unwrap();
+        reassembler.push(fragments[1].clone()).unwrap();
+        assert_eq!(reassembler.push(fragments[1].clone()), Err(ReassemblyError::DuplicateFragment{sequence: 1}));
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(fragments[1].clone()), Err(ReassemblyError::MissingFragment{expected: 0, found: 1}));
+
+        let mut reassembler = Reassembler::new();
+        reassembler.push(fragments[0].clone()).unwrap();
+        let mut other = fragments[1].clone();
+        other.meta.uid = 8;
+        assert_eq!(reassembler.push(other), Err(ReassemblyError::UidMismatch{expected: 7, found: 8}));
+    }
+}
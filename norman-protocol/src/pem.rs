@@ -0,0 +1,28 @@
+//! Reading the PEM certificates and keys both ends use for TLS.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Read every certificate in the PEM file at `path`, failing if there are none.
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|error| format!("Problem reading certificate {}: {}", path, error))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>().map_err(|error| format!("Problem parsing certificate {}: {}", path, error))?;
+
+    match certs.is_empty() {
+        true => Err(format!("No certificates found in {}", path)),
+        false => Ok(certs),
+    }
+}
+
+/// Read the first private key in the PEM file at `path`.
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|error| format!("Problem reading key {}: {}", path, error))?;
+
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("No private key found in {}", path)),
+        Err(error) => Err(format!("Problem parsing key {}: {}", path, error)),
+    }
+}
@@ -0,0 +1,214 @@
+//! Reassembling packets from a byte stream.
+
+use std::io::{self, Read};
+
+use crate::{NormanPacket, ReadError, WireFormat, FRAME_HEADER_LEN, FRAME_MAGIC, TERMINATOR};
+
+/// The largest packet a `PacketReader` accepts unless told otherwise.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// Reads whole packets from any `Read`, however the bytes happen to be split
+/// across reads.
+///
+/// Bytes are buffered until a complete binary frame, or a text packet ending in
+/// `NORMAN/END`, has arrived. Anything read past the end of one packet is kept
+/// for the next, so several packets can be read back to back from one stream.
+pub struct PacketReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    max_packet_size: usize,
+    format: Option<WireFormat>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(inner: R) -> PacketReader<R> {
+        PacketReader::with_max_packet_size(inner, DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    /// Create a reader that gives up on any packet longer than `max_packet_size` bytes.
+    pub fn with_max_packet_size(inner: R, max_packet_size: usize) -> PacketReader<R> {
+        PacketReader {
+            inner,
+            buffer: Vec::new(),
+            max_packet_size,
+            format: None,
+        }
+    }
+
+    /// Read the next packet from the stream.
+    ///
+    /// Returns `Ok(None)` when the stream ends cleanly between packets. If the
+    /// underlying reader fails, any partial packet stays buffered so the call
+    /// can be retried, which makes read timeouts safe to use.
+    pub fn read_packet(&mut self) -> Result<Option<NormanPacket>, ReadError> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            if let Some(len) = self.complete_packet_len()? {
+                if len > self.max_packet_size {
+                    return Err(ReadError::TooLarge{limit: self.max_packet_size});
+                }
+                let bytes: Vec<u8> = self.buffer.drain(..len).collect();
+                self.format = Some(WireFormat::detect(&bytes));
+
+                return NormanPacket::decode(&bytes).map(Some).map_err(ReadError::Parse);
+            }
+            if self.buffer.len() > self.max_packet_size {
+                return Err(ReadError::TooLarge{limit: self.max_packet_size});
+            }
+
+            let size = match self.inner.read(&mut chunk) {
+                Ok(size) => size,
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(ReadError::Io(error)),
+            };
+            if size == 0 {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(ReadError::UnexpectedEof),
+                };
+            }
+            self.buffer.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    /// The wire format of the last packet read, so replies can be sent back in kind.
+    pub fn format(&self) -> Option<WireFormat> {
+        self.format
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The length of the packet at the front of the buffer, once all of it has arrived.
+    fn complete_packet_len(&self) -> Result<Option<usize>, ReadError> {
+        if self.buffer.len() < FRAME_MAGIC.len() && FRAME_MAGIC.starts_with(&self.buffer) {
+            return Ok(None);
+        }
+
+        match WireFormat::detect(&self.buffer) {
+            WireFormat::Framed => {
+                if self.buffer.len() < FRAME_HEADER_LEN {
+                    return Ok(None);
+                }
+                let body_len = u32::from_be_bytes([self.buffer[6], self.buffer[7], self.buffer[8], self.buffer[9]]) as usize;
+                let packet_len = FRAME_HEADER_LEN + body_len;
+                if packet_len > self.max_packet_size {
+                    return Err(ReadError::TooLarge{limit: self.max_packet_size});
+                }
+
+                Ok(match self.buffer.len() >= packet_len {
+                    true => Some(packet_len),
+                    false => None,
+                })
+            },
+            WireFormat::Text => {
+                let terminator = TERMINATOR.as_bytes();
+
+                Ok(self.buffer
+                    .windows(terminator.len())
+                    .position(|window| window == terminator)
+                    .map(|position| position + terminator.len()))
+            },
+        }
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<NormanPacket, ReadError>;
+
+    fn next(&mut self) -> Option<Result<NormanPacket, ReadError>> {
+        self.read_packet().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseError, RequestType, Service, Status, PROTOCOL_VERSION};
+    use std::io::Cursor;
+
+    /// Hands out its bytes a few at a time, like a slow socket.
+    struct Trickle {
+        bytes: Vec<u8>,
+        position: usize,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = (self.position + self.step).min(self.bytes.len()).min(self.position + buf.len());
+            let size = end - self.position;
+            buf[..size].copy_from_slice(&self.bytes[self.position..end]);
+            self.position = end;
+            Ok(size)
+        }
+    }
+
+    fn packet_with_data(data: String) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::SHELL, RequestType::RETURN, Status::FINE{code: 200}, "None".to_string(), data, false)
+    }
+
+    #[test]
+    fn reads_packets_larger_than_one_read() {
+        let packet = packet_with_data("x".repeat(20_000));
+        let mut reader = PacketReader::new(Trickle{bytes: packet.to_bytes(), position: 0, step: 7});
+
+        assert_eq!(reader.read_packet().unwrap(), Some(packet));
+        assert_eq!(reader.format(), Some(WireFormat::Framed));
+        assert_eq!(reader.read_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_back_to_back_packets() {
+        let first = packet_with_data("first".to_string());
+        let second = packet_with_data("second | with a pipe".to_string());
+        let third = packet_with_data("third".to_string());
+        let mut bytes = first.to_bytes();
+        bytes.extend(second.to_bytes());
+        bytes.extend(third.as_string().into_bytes());
+
+        let packets: Vec<NormanPacket> = PacketReader::new(Cursor::new(bytes)).map(Result::unwrap).collect();
+
+        assert_eq!(packets, vec![first, second, third]);
+    }
+
+    #[test]
+    fn reads_text_packets() {
+        let packet = packet_with_data("echo \"Hello from norman\"".to_string());
+        let mut reader = PacketReader::new(Trickle{bytes: packet.as_string().into_bytes(), position: 0, step: 3});
+
+        assert_eq!(reader.read_packet().unwrap(), Some(packet));
+        assert_eq!(reader.format(), Some(WireFormat::Text));
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let packet = packet_with_data("x".repeat(1_000));
+
+        let mut framed = PacketReader::with_max_packet_size(Cursor::new(packet.to_bytes()), 512);
+        assert!(matches!(framed.read_packet(), Err(ReadError::TooLarge{limit: 512})));
+
+        let mut text = PacketReader::with_max_packet_size(Cursor::new(packet.as_string().into_bytes()), 512);
+        assert!(matches!(text.read_packet(), Err(ReadError::TooLarge{limit: 512})));
+    }
+
+    #[test]
+    fn reports_truncated_and_malformed_packets() {
+        let bytes = packet_with_data("cut short".to_string()).to_bytes();
+        let mut truncated = PacketReader::new(Cursor::new(bytes[..bytes.len() - 3].to_vec()));
+        assert!(matches!(truncated.read_packet(), Err(ReadError::UnexpectedEof)));
+
+        let mut malformed = PacketReader::new(Cursor::new(b"NORMAN/0.1|true|NORMAN/END".to_vec()));
+        assert!(matches!(malformed.read_packet(), Err(ReadError::Parse(ParseError::FieldCount{expected: 11, found: 3}))));
+    }
+}
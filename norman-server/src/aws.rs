@@ -0,0 +1,664 @@
+//! The AWS service: managing EC2 instances through the EC2 Query API.
+//!
+//! Requests are signed with Signature Version 4 using credentials from the
+//! usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+//! environment variables. The endpoint can point anywhere that speaks the API,
+//! such as moto, which is handy for trying things out.
+
+use std::convert::TryFrom;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use roxmltree::{Document, Node};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use sha2::{Digest, Sha256};
+
+use norman_protocol::{NormanPacket, Status};
+
+use crate::execute::format_table;
+use crate::http::{self, HttpError};
+use crate::service::{run_backend, Request, ServiceHandler};
+use crate::CommandOutput;
+
+pub const DEFAULT_AWS_REGION: &str = "us-east-1";
+
+const EC2_API_VERSION: &str = "2016-11-15";
+
+/// How long to wait on the endpoint before giving up.
+const AWS_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const AWS_USAGE: &str = "AWS commands:
+    list                       List instances
+    describe <instance...>     Show the details of instances
+    start <instance...>        Start instances
+    stop <instance...>         Stop instances
+
+Instances can be given by id or by their Name tag.";
+
+/// The ways an AWS request can fail.
+#[derive(Debug)]
+pub enum AwsError {
+    /// The request didn't make sense.
+    Usage(String),
+    /// No credentials were found to sign with.
+    MissingCredentials,
+    /// No instance has the name given.
+    UnknownInstance(String),
+    /// The endpoint couldn't be reached.
+    Io(io::Error),
+    /// The endpoint turned the request down.
+    Api { status: u16, code: String, message: String },
+    /// The endpoint sent back something that couldn't be understood.
+    BadResponse(String),
+}
+
+impl fmt::Display for AwsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AwsError::Usage(message) => write!(f, "{}", message),
+            AwsError::MissingCredentials => write!(f, "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set on the server"),
+            AwsError::UnknownInstance(name) => write!(f, "No instance named \"{}\"", name),
+            AwsError::Io(error) => write!(f, "Problem talking to the AWS endpoint: {}", error),
+            AwsError::Api { status, code, message } => write!(f, "AWS returned {} {}: {}", status, code, message),
+            AwsError::BadResponse(message) => write!(f, "Bad response from the AWS endpoint: {}", message),
+        }
+    }
+}
+
+impl Error for AwsError {}
+
+impl From<io::Error> for AwsError {
+    fn from(error: io::Error) -> AwsError {
+        AwsError::Io(error)
+    }
+}
+
+/// Where the EC2 API is served, parsed from a URL like `https://ec2.eu-west-1.amazonaws.com`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Endpoint, String> {
+        let (tls, rest) = match url.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(format!("AWS endpoint \"{}\" must start with http:// or https://", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let default_port = match tls {
+            true => 443,
+            false => 80,
+        };
+        let bad_port = || format!("AWS endpoint \"{}\" has a bad port", url);
+        let (host, port) = match authority.strip_prefix('[') {
+            //An IPv6 address
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(bad_port)?)),
+                None => return Err(format!("AWS endpoint \"{}\" has an unclosed [", url)),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| bad_port())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(format!("AWS endpoint \"{}\" has no host", url));
+        }
+
+        Ok(Endpoint{tls, host: host.to_string(), port, path: path.to_string()})
+    }
+
+    /// The public EC2 endpoint for a region.
+    pub fn for_region(region: &str) -> Endpoint {
+        Endpoint{tls: true, host: format!("ec2.{}.amazonaws.com", region), port: 443, path: String::from("/")}
+    }
+
+    /// The `Host` header, which leaves out the port when it's the default.
+    fn host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.tls {
+            true => "https",
+            false => "http",
+        };
+        write!(f, "{}://{}{}", scheme, self.host_header(), self.path)
+    }
+}
+
+/// An access key to sign requests with.
+#[derive(PartialEq, Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    /// Read the credentials from the standard environment variables.
+    pub fn from_env() -> Result<AwsCredentials, AwsError> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(AwsCredentials{access_key_id, secret_access_key, session_token: var("AWS_SESSION_TOKEN")}),
+            _ => Err(AwsError::MissingCredentials),
+        }
+    }
+}
+
+impl fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AwsCredentials({}, ..)", self.access_key_id)
+    }
+}
+
+/// The parts of an instance worth showing.
+#[derive(PartialEq, Clone, Debug, Default)]
+struct Instance {
+    id: String,
+    name: String,
+    instance_type: String,
+    state: String,
+    image: String,
+    private_ip: String,
+    public_ip: String,
+    launch_time: String,
+}
+
+/// A client for the EC2 API in one region.
+pub struct Aws {
+    endpoint: Endpoint,
+    region: String,
+    credentials: AwsCredentials,
+}
+
+impl Aws {
+    pub fn new(endpoint: Endpoint, region: &str, credentials: AwsCredentials) -> Aws {
+        Aws{endpoint, region: region.to_string(), credentials}
+    }
+
+    /// Carry out an AWS request given as a verb and its arguments.
+    pub fn run(&self, argv: &[String]) -> Result<CommandOutput, AwsError> {
+        let args: Vec<&str> = argv.iter().map(String::as_str).collect();
+
+        let stdout = match args.as_slice() {
+            ["list"] => self.list()?,
+            ["describe", instances @ ..] if !instances.is_empty() => self.describe(instances)?,
+            ["start", instances @ ..] if !instances.is_empty() => self.change_state("StartInstances", instances)?,
+            ["stop", instances @ ..] if !instances.is_empty() => self.change_state("StopInstances", instances)?,
+            [] => return Err(AwsError::Usage(String::from("No AWS command given"))),
+            [verb, ..] => return Err(AwsError::Usage(format!("Can't make sense of AWS command \"{}\"", verb))),
+        };
+
+        Ok(CommandOutput{stdout, stderr: String::new(), exit_code: 0})
+    }
+
+    /// A table of every instance in the region.
+    pub fn list(&self) -> Result<String, AwsError> {
+        let mut rows = vec![vec![String::from("INSTANCE ID"), String::from("NAME"), String::from("TYPE"), String::from("STATE")]];
+        for instance in self.describe_instances(Vec::new())? {
+            rows.push(vec![instance.id, instance.name, instance.instance_type, instance.state]);
+        }
+        Ok(format_table(&rows))
+    }
+
+    /// The details of some instances, one block each.
+    pub fn describe(&self, instances: &[&str]) -> Result<String, AwsError> {
+        let ids = self.instance_ids(instances)?;
+
+        let blocks: Vec<String> = self.describe_instances(numbered("InstanceId", &ids))?.into_iter().map(|instance| {
+            let mut block = match instance.name.is_empty() {
+                true => format!("{}\n", instance.id),
+                false => format!("{} ({})\n", instance.id, instance.name),
+            };
+            let fields = [
+                ("type", instance.instance_type), ("state", instance.state), ("image", instance.image),
+                ("private ip", instance.private_ip), ("public ip", instance.public_ip), ("launched", instance.launch_time),
+            ];
+            for (label, value) in fields.iter().filter(|(_, value)| !value.is_empty()) {
+                block.push_str(&format!("    {}: {}\n", label, value));
+            }
+            block
+        }).collect();
+        Ok(blocks.join("\n"))
+    }
+
+    /// Start or stop instances, reporting the state each moves between.
+    pub fn change_state(&self, action: &str, instances: &[&str]) -> Result<String, AwsError> {
+        let ids = self.instance_ids(instances)?;
+        let xml = self.call(action, numbered("InstanceId", &ids))?;
+        let document = parse(&xml)?;
+
+        let mut report = String::new();
+        for item in descendants(document.root_element(), "instancesSet").flat_map(|set| children(set, "item")) {
+            report.push_str(&format!("{}: {} -> {}\n", text(item, &["instanceId"]), text(item, &["previousState", "name"]), text(item, &["currentState", "name"])));
+        }
+        Ok(report)
+    }
+
+    /// Turn instance names into ids, leaving ids as they are.
+    fn instance_ids(&self, instances: &[&str]) -> Result<Vec<String>, AwsError> {
+        let mut ids = Vec::new();
+        for instance in instances {
+            if instance.starts_with("i-") {
+                ids.push(instance.to_string());
+                continue;
+            }
+
+            //Terminated instances hang around for a while, but can't be started or stopped
+            let mut params = vec![(String::from("Filter.1.Name"), String::from("tag:Name")), (String::from("Filter.1.Value.1"), instance.to_string())];
+            params.push((String::from("Filter.2.Name"), String::from("instance-state-name")));
+            params.extend(numbered("Filter.2.Value", &["pending", "running", "stopping", "stopped"]));
+
+            let named = self.describe_instances(params)?;
+            if named.is_empty() {
+                return Err(AwsError::UnknownInstance(instance.to_string()));
+            }
+            ids.extend(named.into_iter().map(|instance| instance.id));
+        }
+        Ok(ids)
+    }
+
+    fn describe_instances(&self, params: Vec<(String, String)>) -> Result<Vec<Instance>, AwsError> {
+        let xml = self.call("DescribeInstances", params)?;
+        let document = parse(&xml)?;
+
+        let reservations = descendants(document.root_element(), "reservationSet").flat_map(|set| children(set, "item"));
+        let instances = reservations.flat_map(|reservation| children(reservation, "instancesSet")).flat_map(|set| children(set, "item"));
+        Ok(instances.map(|item| {
+            let name = children(item, "tagSet").flat_map(|set| children(set, "item"))
+                .find(|tag| text(*tag, &["key"]) == "Name")
+                .map(|tag| text(tag, &["value"]))
+                .unwrap_or_default();
+
+            Instance {
+                id: text(item, &["instanceId"]),
+                name,
+                instance_type: text(item, &["instanceType"]),
+                state: text(item, &["instanceState", "name"]),
+                image: text(item, &["imageId"]),
+                private_ip: text(item, &["privateIpAddress"]),
+                public_ip: text(item, &["ipAddress"]),
+                launch_time: text(item, &["launchTime"]),
+            }
+        }).collect())
+    }
+
+    /// Make a signed API call, giving back the XML of a successful response.
+    fn call(&self, action: &str, params: Vec<(String, String)>) -> Result<String, AwsError> {
+        let mut form = vec![(String::from("Action"), action.to_string()), (String::from("Version"), String::from(EC2_API_VERSION))];
+        form.extend(params);
+        let body = form.iter().map(|(name, value)| format!("{}={}", encode(name), encode(value))).collect::<Vec<String>>().join("&");
+
+        let mut headers = vec![
+            (String::from("content-type"), String::from("application/x-www-form-urlencoded; charset=utf-8")),
+            (String::from("host"), self.endpoint.host_header()),
+            (String::from("x-amz-date"), amz_date(now())),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push((String::from("x-amz-security-token"), token.clone()));
+        }
+        let authorization = authorization(&self.credentials, &self.region, "ec2", "POST", &self.endpoint.path, &headers, body.as_bytes());
+
+        //Host goes in separately, so leave it out here
+        let mut sent: Vec<(&str, String)> = headers.iter().filter(|(name, _)| name != "host").map(|(name, value)| (name.as_str(), value.clone())).collect();
+        sent.push(("authorization", authorization));
+
+        let response = self.send(&sent, body.as_bytes())?;
+        let xml = String::from_utf8_lossy(&response.body).into_owned();
+        if response.is_success() {
+            return Ok(xml);
+        }
+
+        let error = parse(&xml).ok().and_then(|document| descendants(document.root_element(), "Error").next().map(|error| (text(error, &["Code"]), text(error, &["Message"]))));
+        let (code, message) = error.unwrap_or_else(|| (String::from("Unknown"), xml.trim().to_string()));
+        Err(AwsError::Api{status: response.status, code, message})
+    }
+
+    fn send(&self, headers: &[(&str, String)], body: &[u8]) -> Result<http::Response, AwsError> {
+        let mut stream = TcpStream::connect((self.endpoint.host.as_str(), self.endpoint.port))?;
+        stream.set_read_timeout(Some(AWS_TIMEOUT))?;
+        stream.set_write_timeout(Some(AWS_TIMEOUT))?;
+        let host = self.endpoint.host_header();
+
+        let response = match self.endpoint.tls {
+            true => {
+                let server_name = ServerName::try_from(self.endpoint.host.clone()).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+                let connection = ClientConnection::new(tls_config(), server_name).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                http::exchange(&mut StreamOwned::new(connection, stream), "POST", &host, &self.endpoint.path, headers, body)
+            },
+            false => http::exchange(&mut stream, "POST", &host, &self.endpoint.path, headers, body),
+        };
+        response.map_err(|error| match error {
+            HttpError::Io(error) => AwsError::Io(error),
+            HttpError::BadResponse(message) => AwsError::BadResponse(message),
+        })
+    }
+}
+
+/// The AWS service. Credentials are read from the environment for each
+/// request, so a server without them can still offer the other services.
+pub struct AwsService {
+    endpoint: Endpoint,
+    region: String,
+}
+
+impl AwsService {
+    pub fn new(endpoint: Endpoint, region: &str) -> AwsService {
+        AwsService{endpoint, region: region.to_string()}
+    }
+}
+
+impl ServiceHandler for AwsService {
+    fn handle(&self, request: &Request) -> Vec<NormanPacket> {
+        let (endpoint, region) = (self.endpoint.clone(), self.region.clone());
+        let run = move |argv: &[String]| AwsCredentials::from_env().and_then(|credentials| Aws::new(endpoint, &region, credentials).run(argv));
+        let status = |error: &AwsError| match error {
+            AwsError::Usage(_) => Status::BAD_REQUEST,
+            AwsError::UnknownInstance(_) => Status::NOT_FOUND,
+            AwsError::Api{code, ..} if code == "InvalidInstanceID.NotFound" => Status::NOT_FOUND,
+            AwsError::Io(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Status::TIMEOUT,
+            _ => Status::INTERNAL_ERROR,
+        };
+        vec![run_backend(request, run, status, AWS_USAGE)]
+    }
+}
+
+fn tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore{roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()};
+    Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth())
+}
+
+/// The Signature Version 4 `Authorization` header for a request.
+///
+/// `headers` are the headers to sign, with lowercase names, and must include
+/// `host` and `x-amz-date`.
+fn authorization(credentials: &AwsCredentials, region: &str, service: &str, method: &str, path: &str, headers: &[(String, String)], body: &[u8]) -> String {
+    let mut headers = headers.to_vec();
+    headers.sort();
+    let timestamp = headers.iter().find(|(name, _)| name == "x-amz-date").map(|(_, value)| value.as_str()).unwrap_or("");
+    let date = &timestamp[..timestamp.len().min(8)];
+
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>().join(";");
+    let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}", method, path, canonical_headers, signed_headers, hex::encode(Sha256::digest(body)));
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())));
+
+    let mut key = hmac(format!("AWS4{}", credentials.secret_access_key).as_bytes(), date);
+    for part in &[region, service, "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+
+    format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", credentials.access_key_id, scope, signed_headers, signature)
+}
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A Unix time as `YYYYMMDD'T'HHMMSS'Z'`.
+fn amz_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    //Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Percent-encode everything but the characters AWS leaves alone.
+fn encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// List parameters, which the Query API numbers from one: `InstanceId.1`, `InstanceId.2`, ...
+fn numbered<S: AsRef<str>>(name: &str, values: &[S]) -> Vec<(String, String)> {
+    values.iter().enumerate().map(|(index, value)| (format!("{}.{}", name, index + 1), value.as_ref().to_string())).collect()
+}
+
+fn parse(xml: &str) -> Result<Document<'_>, AwsError> {
+    Document::parse(xml).map_err(|error| AwsError::BadResponse(error.to_string()))
+}
+
+//Responses are namespaced by API version, so elements are matched by local name
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |child| child.tag_name().name() == name)
+}
+
+fn descendants<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.descendants().filter(move |child| child.tag_name().name() == name)
+}
+
+/// The text at the end of a path of child elements, or nothing if it isn't there.
+fn text(node: Node, path: &[&str]) -> String {
+    let mut node = node;
+    for name in path {
+        node = match children(node, name).next() {
+            Some(child) => child,
+            None => return String::new(),
+        };
+    }
+    node.text().unwrap_or("").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn credentials() -> AwsCredentials {
+        AwsCredentials{access_key_id: String::from("AKIDEXAMPLE"), secret_access_key: String::from("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"), session_token: None}
+    }
+
+    /// The header lines and body of a request the stand-in endpoint received.
+    type Seen = (Vec<String>, String);
+
+    /// A stand-in endpoint that answers each request in turn with the next
+    /// canned XML and hands back the requests it saw.
+    fn mock_endpoint(responses: Vec<(&'static str, &'static str)>) -> (Aws, thread::JoinHandle<Vec<Seen>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut seen = Vec::new();
+            for (status, xml) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    headers.push(line.trim_end().to_string());
+                }
+                let len = headers.iter().find_map(|header| header.to_ascii_lowercase().strip_prefix("content-length:").map(|len| len.trim().parse().unwrap())).unwrap_or(0);
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                seen.push((headers, String::from_utf8(body).unwrap()));
+
+                let response = format!("HTTP/1.1 {}\r\nContent-Type: text/xml;charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}", status, xml.len(), xml);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            seen
+        });
+
+        (Aws::new(endpoint, "us-east-1", credentials()), server)
+    }
+
+    const INSTANCES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+    <requestId>8f7724cf-496f-496e-8fe3-example</requestId>
+    <reservationSet>
+        <item>
+            <reservationId>r-1234567890abcdef0</reservationId>
+            <instancesSet>
+                <item>
+                    <instanceId>i-0598c7d356eba48d7</instanceId>
+                    <imageId>ami-0abcdef1234567890</imageId>
+                    <instanceState><code>16</code><name>running</name></instanceState>
+                    <instanceType>t3.micro</instanceType>
+                    <launchTime>2019-10-17T09:12:41.000Z</launchTime>
+                    <privateIpAddress>10.0.0.12</privateIpAddress>
+                    <ipAddress>54.194.252.215</ipAddress>
+                    <tagSet>
+                        <item><key>team</key><value>ops</value></item>
+                        <item><key>Name</key><value>Ubuntu 19.10 Server</value></item>
+                    </tagSet>
+                </item>
+                <item>
+                    <instanceId>i-1234567890abcdef0</instanceId>
+                    <instanceState><code>80</code><name>stopped</name></instanceState>
+                    <instanceType>m5.large</instanceType>
+                </item>
+            </instancesSet>
+        </item>
+    </reservationSet>
+</DescribeInstancesResponse>"#;
+
+    #[test]
+    fn list_instances() {
+        let (aws, server) = mock_endpoint(vec![("200 OK", INSTANCES)]);
+
+        let output = aws.run(&[String::from("list")]).unwrap();
+
+        assert_eq!(output.stdout, "\
+INSTANCE ID           NAME                  TYPE       STATE
+i-0598c7d356eba48d7   Ubuntu 19.10 Server   t3.micro   running
+i-1234567890abcdef0                         m5.large   stopped
+");
+        let seen = server.join().unwrap();
+        let (headers, body) = &seen[0];
+        assert_eq!(headers[0], "POST / HTTP/1.1");
+        assert!(headers.iter().any(|header| header.starts_with("authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/") && header.contains("/us-east-1/ec2/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature=")));
+        assert_eq!(body, "Action=DescribeInstances&Version=2016-11-15");
+    }
+
+    #[test]
+    fn start_by_name() {
+        let started = r#"<StartInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <instancesSet><item>
+                <instanceId>i-0598c7d356eba48d7</instanceId>
+                <currentState><code>0</code><name>pending</name></currentState>
+                <previousState><code>80</code><name>stopped</name></previousState>
+            </item></instancesSet>
+        </StartInstancesResponse>"#;
+        let named = r#"<DescribeInstancesResponse><reservationSet><item><instancesSet><item><instanceId>i-0598c7d356eba48d7</instanceId></item></instancesSet></item></reservationSet></DescribeInstancesResponse>"#;
+        let (aws, server) = mock_endpoint(vec![("200 OK", named), ("200 OK", started)]);
+
+        let output = aws.run(&[String::from("start"), String::from("Ubuntu 19.10 Server")]).unwrap();
+
+        assert_eq!(output.stdout, "i-0598c7d356eba48d7: stopped -> pending\n");
+        let seen = server.join().unwrap();
+        assert_eq!(seen[0].1, "Action=DescribeInstances&Version=2016-11-15&Filter.1.Name=tag%3AName&Filter.1.Value.1=Ubuntu%2019.10%20Server&Filter.2.Name=instance-state-name&Filter.2.Value.1=pending&Filter.2.Value.2=running&Filter.2.Value.3=stopping&Filter.2.Value.4=stopped");
+        assert_eq!(seen[1].1, "Action=StartInstances&Version=2016-11-15&InstanceId.1=i-0598c7d356eba48d7");
+    }
+
+    #[test]
+    fn describe_and_errors() {
+        let not_found = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Response><Errors><Error><Code>InvalidInstanceID.NotFound</Code><Message>The instance ID 'i-0000000000000000f' does not exist</Message></Error></Errors><RequestID>ea966190</RequestID></Response>"#;
+        let (aws, server) = mock_endpoint(vec![("200 OK", INSTANCES), ("400 Bad Request", not_found), ("200 OK", "<DescribeInstancesResponse><reservationSet/></DescribeInstancesResponse>")]);
+
+        let output = aws.run(&[String::from("describe"), String::from("i-0598c7d356eba48d7")]).unwrap();
+        assert_eq!(output.stdout, "\
+i-0598c7d356eba48d7 (Ubuntu 19.10 Server)
+    type: t3.micro
+    state: running
+    image: ami-0abcdef1234567890
+    private ip: 10.0.0.12
+    public ip: 54.194.252.215
+    launched: 2019-10-17T09:12:41.000Z
+
+i-1234567890abcdef0
+    type: m5.large
+    state: stopped
+");
+
+        match aws.run(&[String::from("stop"), String::from("i-0000000000000000f")]) {
+            Err(AwsError::Api{status: 400, code, ..}) => assert_eq!(code, "InvalidInstanceID.NotFound"),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        assert!(matches!(aws.run(&[String::from("stop"), String::from("nobody")]), Err(AwsError::UnknownInstance(_))));
+        assert!(matches!(aws.run(&[String::from("terminate"), String::from("i-0598c7d356eba48d7")]), Err(AwsError::Usage(_))));
+        assert!(matches!(aws.run(&[String::from("start")]), Err(AwsError::Usage(_))));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn signs_like_the_aws_test_suite() {
+        //The get-vanilla case from the Signature Version 4 test suite
+        let headers = vec![(String::from("host"), String::from("example.amazonaws.com")), (String::from("x-amz-date"), String::from("20150830T123600Z"))];
+
+        assert_eq!(authorization(&credentials(), "us-east-1", "service", "GET", "/", &headers, b""),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31");
+        assert_eq!(amz_date(1_440_938_160), "20150830T123600Z");
+        assert_eq!(amz_date(951_868_799), "20000229T235959Z");
+    }
+
+    #[test]
+    fn parse_endpoints() {
+        assert_eq!(Endpoint::parse("https://ec2.eu-west-1.amazonaws.com").unwrap(), Endpoint::for_region("eu-west-1"));
+        assert_eq!(Endpoint::parse("http://localhost:5000/").unwrap().to_string(), "http://localhost:5000/");
+        assert_eq!(Endpoint::parse("http://[::1]:5000").unwrap().host_header(), "[::1]:5000");
+        assert_eq!(Endpoint::parse("https://[::1]").unwrap().host_header(), "[::1]");
+        assert!(Endpoint::parse("localhost:5000").is_err());
+        assert!(Endpoint::parse("http://localhost:port").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::LogLevel;
+
+/// Settings read from a TOML config file. Anything left out falls back to
+/// the command-line flags and then to the defaults.
+///
+/// ```toml
+/// bind = ["0.0.0.0", "[::]:7879"]
+/// port = 7878
+/// threads = 8
+/// allowed_services = ["SHELL", "DOCKER"]
+/// log_level = "warn"
+/// default_shell = "sh"
+/// max_packet_size = 1048576
+/// key_file = "/etc/norman/key"
+/// require_encryption = true
+/// tls_cert = "/etc/norman/server.pem"
+/// tls_key = "/etc/norman/server.key"
+/// tls_client_ca = "/etc/norman/team-ca.pem"
+/// docker_socket = "/run/user/1000/docker.sock"
+/// aws_region = "eu-west-1"
+/// aws_endpoint = "http://localhost:5000"
+/// server_name = "build-01"
+/// idle_timeout = 60
+/// max_connections = 64
+/// max_in_flight = 16
+/// max_background = 16
+///
+/// [clients.alice]
+/// key = "5d41402abc4b2a76b9719d911017c592"
+/// certificate = "alice-laptop"
+///
+/// [clients.junior]
+/// key = "7d793037a0760186574b0282f2f435e7"
+/// services = ["SHELL"]
+/// executables = ["ls", "cat", "df", "uptime"]
+/// deny = ['/etc/shadow']
+/// allow_shell = true
+/// user = "nobody"
+/// ```
+#[derive(Deserialize, PartialEq, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub bind: Option<Vec<String>>,
+    pub port: Option<u16>,
+    pub threads: Option<usize>,
+    pub callback_port: Option<u16>,
+    pub allowed_services: Option<Vec<String>>,
+    pub log_level: Option<LogLevel>,
+    pub max_packet_size: Option<usize>,
+    pub fragment_size: Option<usize>,
+    pub default_shell: Option<String>,
+    pub key_file: Option<String>,
+    pub require_encryption: Option<bool>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+    pub docker_socket: Option<String>,
+    pub aws_region: Option<String>,
+    /// EC2 API URL, when it isn't the public endpoint for `aws_region`.
+    pub aws_endpoint: Option<String>,
+    /// Name the server gives clients that ask for its capabilities.
+    pub server_name: Option<String>,
+    /// Seconds a connection may sit with nothing sent and nothing running before it is closed.
+    pub idle_timeout: Option<u64>,
+    /// Most connections served at once.
+    pub max_connections: Option<usize>,
+    /// Most requests one connection may have running or waiting at once.
+    pub max_in_flight: Option<usize>,
+    /// Most fire-and-forget requests left running at once.
+    pub max_background: Option<usize>,
+    /// The clients allowed to send requests, by id. Anyone may when this is unset.
+    pub clients: Option<BTreeMap<String, ClientSettings>>,
+}
+
+/// Settings for one client in the config file's `[clients.<id>]` table.
+/// Everything but the key restricts what the client may run; see `Policy`.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSettings {
+    /// Hex key the client signs its requests with.
+    pub key: String,
+    /// Common name the client's TLS certificate must carry. Requests signed
+    /// as this client from a connection without it are refused.
+    pub certificate: Option<String>,
+    /// Services the client may use.
+    pub services: Option<Vec<String>>,
+    /// Programs the client may run, as bare names or full paths.
+    pub executables: Option<Vec<String>>,
+    /// Regexes one of which the whole command must match.
+    pub allow: Option<Vec<String>>,
+    /// Regexes none of which may match anywhere in the command.
+    pub deny: Option<Vec<String>>,
+    /// Whether commands may still be run through a shell when executables,
+    /// allow or deny are set. Off unless turned on.
+    pub allow_shell: Option<bool>,
+    /// Directory the client's commands run in.
+    pub working_dir: Option<String>,
+    /// User the client's commands run as.
+    pub user: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<ConfigFile, String> {
+        let contents = fs::read_to_string(path).map_err(|error| format!("Problem reading config file {}: {}", path, error))?;
+
+        toml::from_str(&contents).map_err(|error| format!("Problem parsing config file {}: {}", path, error))
+    }
+
+    /// Fill in anything this config leaves unset from `other`.
+    pub fn or(self, other: ConfigFile) -> ConfigFile {
+        ConfigFile {
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            threads: self.threads.or(other.threads),
+            callback_port: self.callback_port.or(other.callback_port),
+            allowed_services: self.allowed_services.or(other.allowed_services),
+            log_level: self.log_level.or(other.log_level),
+            max_packet_size: self.max_packet_size.or(other.max_packet_size),
+            fragment_size: self.fragment_size.or(other.fragment_size),
+            default_shell: self.default_shell.or(other.default_shell),
+            key_file: self.key_file.or(other.key_file),
+            require_encryption: self.require_encryption.or(other.require_encryption),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            tls_client_ca: self.tls_client_ca.or(other.tls_client_ca),
+            docker_socket: self.docker_socket.or(other.docker_socket),
+            aws_region: self.aws_region.or(other.aws_region),
+            aws_endpoint: self.aws_endpoint.or(other.aws_endpoint),
+            server_name: self.server_name.or(other.server_name),
+            idle_timeout: self.idle_timeout.or(other.idle_timeout),
+            max_connections: self.max_connections.or(other.max_connections),
+            max_in_flight: self.max_in_flight.or(other.max_in_flight),
+            max_background: self.max_background.or(other.max_background),
+            clients: self.clients.or(other.clients),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config_file() {
+        let config: ConfigFile = toml::from_str(r#"
+            bind = ["0.0.0.0"]
+            threads = 8
+            allowed_services = ["SHELL"]
+            log_level = "debug"
+        "#).unwrap();
+
+        assert_eq!(config, ConfigFile {
+            bind: Some(vec![String::from("0.0.0.0")]),
+            threads: Some(8),
+            allowed_services: Some(vec![String::from("SHELL")]),
+            log_level: Some(LogLevel::Debug),
+            ..ConfigFile::default()
+        });
+        assert!(toml::from_str::<ConfigFile>("thread_count = 8").is_err());
+    }
+
+    #[test]
+    fn parse_clients() {
+        let config: ConfigFile = toml::from_str(r#"
+            [clients.alice]
+            key = "00112233445566778899aabbccddeeff"
+
+            [clients.bob]
+            key = "ffeeddccbbaa99887766554433221100"
+        "#).unwrap();
+
+        let clients = config.clients.unwrap();
+        assert_eq!(clients.keys().collect::<Vec<&String>>(), vec!["alice", "bob"]);
+        assert_eq!(clients["alice"].key, "00112233445566778899aabbccddeeff");
+        assert!(toml::from_str::<ConfigFile>("[clients.alice]\nsecret = \"x\"").is_err());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let flags = ConfigFile {port: Some(9000), ..ConfigFile::default()};
+        let file = ConfigFile {port: Some(7000), threads: Some(2), ..ConfigFile::default()};
+
+        let merged = flags.or(file);
+
+        assert_eq!(merged.port, Some(9000));
+        assert_eq!(merged.threads, Some(2));
+    }
+}
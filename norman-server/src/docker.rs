@@ -0,0 +1,387 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use norman_protocol::{NormanPacket, Status};
+use serde_json::{json, Value};
+
+use crate::execute::format_table;
+use crate::http::{self, HttpError, Response};
+use crate::service::{run_backend, Request, ServiceHandler};
+use crate::CommandOutput;
+
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// How long to wait on the engine before giving up. Stopping a container can take a while.
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub const DOCKER_USAGE: &str = "DOCKER commands:
+    list [--all]               List running containers, or all of them
+    start <container>          Start a container
+    stop <container>           Stop a container
+    restart <container>        Restart a container
+    exec <container> <cmd...>  Run a command inside a running container";
+
+/// The ways a DOCKER request can fail.
+#[derive(Debug)]
+pub enum DockerError {
+    /// The request didn't make sense.
+    Usage(String),
+    /// The engine couldn't be reached.
+    Io(io::Error),
+    /// The engine turned the request down.
+    Api { status: u16, message: String },
+    /// The engine sent back something that couldn't be understood.
+    BadResponse(String),
+}
+
+impl fmt::Display for DockerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DockerError::Usage(message) => write!(f, "{}", message),
+            DockerError::Io(error) => write!(f, "Problem talking to the Docker engine: {}", error),
+            DockerError::Api { status, message } => write!(f, "Docker engine returned {}: {}", status, message),
+            DockerError::BadResponse(message) => write!(f, "Bad response from the Docker engine: {}", message),
+        }
+    }
+}
+
+impl Error for DockerError {}
+
+impl From<io::Error> for DockerError {
+    fn from(error: io::Error) -> DockerError {
+        DockerError::Io(error)
+    }
+}
+
+/// A client for the Docker engine API, spoken over the engine's Unix socket.
+#[derive(Clone, Debug)]
+pub struct Docker {
+    socket: PathBuf,
+}
+
+impl Docker {
+    pub fn new<P: Into<PathBuf>>(socket: P) -> Docker {
+        Docker{socket: socket.into()}
+    }
+
+    /// Carry out a DOCKER request given as a verb and its arguments.
+    pub fn run(&self, argv: &[String]) -> Result<CommandOutput, DockerError> {
+        let args: Vec<&str> = argv.iter().map(String::as_str).collect();
+
+        let stdout = match args.as_slice() {
+            ["list"] | ["ps"] => self.list(false)?,
+            ["list", "--all"] | ["list", "-a"] | ["ps", "--all"] | ["ps", "-a"] => self.list(true)?,
+            ["start", container] => {
+                self.container_action(container, "start")?;
+                format!("Started {}\n", container)
+            },
+            ["stop", container] => {
+                self.container_action(container, "stop")?;
+                format!("Stopped {}\n", container)
+            },
+            ["restart", container] => {
+                self.container_action(container, "restart")?;
+                format!("Restarted {}\n", container)
+            },
+            ["exec", container, command @ ..] if !command.is_empty() => return self.exec(container, command),
+            [] => return Err(DockerError::Usage(String::from("No DOCKER command given"))),
+            [verb, ..] => return Err(DockerError::Usage(format!("Can't make sense of DOCKER command \"{}\"", verb))),
+        };
+
+        Ok(CommandOutput{stdout, stderr: String::new(), exit_code: 0})
+    }
+
+    /// A table of containers, like `docker ps` prints.
+    pub fn list(&self, all: bool) -> Result<String, DockerError> {
+        let path = match all {
+            true => "/containers/json?all=true",
+            false => "/containers/json",
+        };
+        let containers = json(self.request("GET", path, None)?)?;
+        let containers = containers.as_array().ok_or_else(|| DockerError::BadResponse(String::from("container list is not an array")))?;
+
+        let mut rows = vec![vec![String::from("CONTAINER ID"), String::from("IMAGE"), String::from("STATUS"), String::from("NAMES")]];
+        for container in containers {
+            let field = |name: &str| container[name].as_str().unwrap_or("").to_string();
+            let names = container["Names"].as_array().map(|names| {
+                names.iter().filter_map(Value::as_str).map(|name| name.trim_start_matches('/')).collect::<Vec<&str>>().join(",")
+            }).unwrap_or_default();
+
+            rows.push(vec![field("Id").chars().take(12).collect(), field("Image"), field("Status"), names]);
+        }
+        Ok(format_table(&rows))
+    }
+
+    /// Start, stop or restart a container.
+    pub fn container_action(&self, container: &str, action: &str) -> Result<(), DockerError> {
+        check_container_name(container)?;
+
+        let response = self.request("POST", &format!("/containers/{}/{}", container, action), None)?;
+        match response.status {
+            //Already in the state asked for
+            304 => Ok(()),
+            _ => success(response).map(|_| ()),
+        }
+    }
+
+    /// Run a command inside a running container and wait for it to finish.
+    pub fn exec(&self, container: &str, command: &[&str]) -> Result<CommandOutput, DockerError> {
+        check_container_name(container)?;
+
+        let create = json!({"AttachStdout": true, "AttachStderr": true, "Tty": false, "Cmd": command});
+        let exec = json(self.request("POST", &format!("/containers/{}/exec", container), Some(create))?)?;
+        let id = exec["Id"].as_str().ok_or_else(|| DockerError::BadResponse(String::from("exec has no id")))?.to_string();
+
+        let output = success(self.request("POST", &format!("/exec/{}/start", id), Some(json!({"Detach": false, "Tty": false})))?)?;
+        let (stdout, stderr) = demultiplex(&output)?;
+
+        let inspect = json(self.request("GET", &format!("/exec/{}/json", id), None)?)?;
+        let exit_code = inspect["ExitCode"].as_i64().ok_or_else(|| DockerError::BadResponse(String::from("exec has no exit code")))?;
+
+        Ok(CommandOutput {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            exit_code: exit_code as i32,
+        })
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<Value>) -> Result<Response, DockerError> {
+        let mut stream = self.connect()?;
+
+        let (headers, body) = match body {
+            Some(body) => (vec![("Content-Type", String::from("application/json"))], body.to_string()),
+            None => (Vec::new(), String::new()),
+        };
+        http::exchange(&mut stream, method, "docker", path, &headers, body.as_bytes()).map_err(|error| match error {
+            HttpError::Io(error) => DockerError::Io(error),
+            HttpError::BadResponse(message) => DockerError::BadResponse(message),
+        })
+    }
+
+    #[cfg(unix)]
+    fn connect(&self) -> io::Result<std::os::unix::net::UnixStream> {
+        let stream = std::os::unix::net::UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(DOCKER_TIMEOUT))?;
+        stream.set_write_timeout(Some(DOCKER_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    #[cfg(not(unix))]
+    fn connect(&self) -> io::Result<std::net::TcpStream> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't reach the Docker socket {} on this platform", self.socket.display())))
+    }
+}
+
+impl ServiceHandler for Docker {
+    fn handle(&self, request: &Request) -> Vec<NormanPacket> {
+        let docker = self.clone();
+        let status = |error: &DockerError| match error {
+            DockerError::Usage(_) => Status::BAD_REQUEST,
+            DockerError::Api{status: 404, ..} => Status::NOT_FOUND,
+            DockerError::Io(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Status::TIMEOUT,
+            _ => Status::INTERNAL_ERROR,
+        };
+        vec![run_backend(request, move |argv| docker.run(argv), status, DOCKER_USAGE)]
+    }
+}
+
+/// Container names and ids go into request paths, so only allow the characters Docker does.
+fn check_container_name(container: &str) -> Result<(), DockerError> {
+    let valid = !container.is_empty() && container.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    match valid {
+        true => Ok(()),
+        false => Err(DockerError::Usage(format!("\"{}\" is not a container name or id", container))),
+    }
+}
+
+/// The body of a successful response, or the engine's error message.
+fn success(response: Response) -> Result<Vec<u8>, DockerError> {
+    if response.is_success() {
+        return Ok(response.body);
+    }
+
+    let message = serde_json::from_slice::<Value>(&response.body).ok()
+        .and_then(|body| body["message"].as_str().map(String::from))
+        .unwrap_or_else(|| String::from_utf8_lossy(&response.body).trim().to_string());
+    Err(DockerError::Api{status: response.status, message})
+}
+
+fn json(response: Response) -> Result<Value, DockerError> {
+    let body = success(response)?;
+    serde_json::from_slice(&body).map_err(|error| DockerError::BadResponse(error.to_string()))
+}
+
+/// Split an exec's output stream into stdout and stderr.
+///
+/// Without a TTY the engine interleaves both on one stream, each piece
+/// prefixed by an eight byte header: the stream it came from, three bytes of
+/// padding and a big-endian `u32` length.
+fn demultiplex(mut stream: &[u8]) -> Result<(Vec<u8>, Vec<u8>), DockerError> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+
+    while !stream.is_empty() {
+        if stream.len() < 8 {
+            return Err(DockerError::BadResponse(String::from("truncated output stream")));
+        }
+        let len = u32::from_be_bytes([stream[4], stream[5], stream[6], stream[7]]) as usize;
+        if stream.len() < 8 + len {
+            return Err(DockerError::BadResponse(String::from("truncated output stream")));
+        }
+        let payload = &stream[8..8 + len];
+        match stream[0] {
+            2 => stderr.extend_from_slice(payload),
+            _ => stdout.extend_from_slice(payload),
+        }
+        stream = &stream[8 + len..];
+    }
+
+    Ok((stdout, stderr))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    /// A stand-in engine that answers each request in turn with the next canned
+    /// response and hands back the request lines it saw.
+    fn mock_engine(name: &str, responses: Vec<Vec<u8>>) -> (Docker, thread::JoinHandle<Vec<String>>) {
+        let dir = std::env::temp_dir().join(format!("norman-docker-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("docker.sock");
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let engine = thread::spawn(move || {
+            let mut seen = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                seen.push(format!("{} {}", request_line.trim_end(), String::from_utf8(body).unwrap()).trim_end().to_string());
+                reader.get_mut().write_all(&response).unwrap();
+            }
+            fs::remove_dir_all(&dir).unwrap();
+            seen
+        });
+
+        (Docker::new(socket), engine)
+    }
+
+    fn response(status: &str, body: &str) -> Vec<u8> {
+        format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body).into_bytes()
+    }
+
+    fn chunked(status: &str, body: &str) -> Vec<u8> {
+        let (first, second) = body.split_at(body.len() / 2);
+        format!("HTTP/1.1 {}\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n", status, first.len(), first, second.len(), second).into_bytes()
+    }
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn list_containers() {
+        let containers = r#"[
+            {"Id": "4f66ad9a0b2e8f1c", "Names": ["/web"], "Image": "nginx:1.25", "Status": "Up 2 hours"},
+            {"Id": "9c1d0e2f3a4b5c6d", "Names": ["/db"], "Image": "postgres", "Status": "Exited (0) 3 days ago"}
+        ]"#;
+        let (docker, engine) = mock_engine("list", vec![chunked("200 OK", containers)]);
+
+        let output = docker.run(&args(&["list", "--all"])).unwrap();
+
+        assert_eq!(output.stdout, "\
+CONTAINER ID   IMAGE        STATUS                  NAMES
+4f66ad9a0b2e   nginx:1.25   Up 2 hours              web
+9c1d0e2f3a4b   postgres     Exited (0) 3 days ago   db
+");
+        assert_eq!(engine.join().unwrap(), vec!["GET /containers/json?all=true HTTP/1.1"]);
+    }
+
+    #[test]
+    fn start_stop_restart() {
+        let (docker, engine) = mock_engine("lifecycle", vec![
+            response("204 No Content", ""),
+            response("304 Not Modified", ""),
+            response("404 Not Found", r#"{"message": "No such container: ghost"}"#),
+        ]);
+
+        assert_eq!(docker.run(&args(&["start", "web"])).unwrap().stdout, "Started web\n");
+        assert_eq!(docker.run(&args(&["stop", "web"])).unwrap().stdout, "Stopped web\n");
+        match docker.run(&args(&["restart", "ghost"])) {
+            Err(DockerError::Api{status: 404, message}) => assert_eq!(message, "No such container: ghost"),
+            other => panic!("expected a 404, got {:?}", other),
+        }
+
+        assert_eq!(engine.join().unwrap(), vec![
+            "POST /containers/web/start HTTP/1.1",
+            "POST /containers/web/stop HTTP/1.1",
+            "POST /containers/ghost/restart HTTP/1.1",
+        ]);
+    }
+
+    #[test]
+    fn exec_in_container() {
+        let mut stream = Vec::new();
+        for (kind, text) in &[(1u8, "total 0\n"), (2, "ls: /nope: No such file\n"), (1, "done\n")] {
+            stream.extend_from_slice(&[*kind, 0, 0, 0]);
+            stream.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            stream.extend_from_slice(text.as_bytes());
+        }
+        let mut start = b"HTTP/1.1 200 OK\r\nContent-Type: application/vnd.docker.multiplexed-stream\r\n\r\n".to_vec();
+        start.extend_from_slice(&stream);
+
+        let (docker, engine) = mock_engine("exec", vec![
+            response("201 Created", r#"{"Id": "e1"}"#),
+            start,
+            response("200 OK", r#"{"ExitCode": 2, "Running": false}"#),
+        ]);
+
+        let output = docker.run(&args(&["exec", "web", "ls", "-l", "/nope"])).unwrap();
+
+        assert_eq!(output, CommandOutput {
+            stdout: String::from("total 0\ndone\n"),
+            stderr: String::from("ls: /nope: No such file\n"),
+            exit_code: 2,
+        });
+        let seen = engine.join().unwrap();
+        assert_eq!(seen[0], r#"POST /containers/web/exec HTTP/1.1 {"AttachStderr":true,"AttachStdout":true,"Cmd":["ls","-l","/nope"],"Tty":false}"#);
+        assert_eq!(seen[1], r#"POST /exec/e1/start HTTP/1.1 {"Detach":false,"Tty":false}"#);
+        assert_eq!(seen[2], "GET /exec/e1/json HTTP/1.1");
+    }
+
+    #[test]
+    fn bad_requests() {
+        let docker = Docker::new("/nonexistent/docker.sock");
+
+        assert!(matches!(docker.run(&args(&[])), Err(DockerError::Usage(_))));
+        assert!(matches!(docker.run(&args(&["rm", "web"])), Err(DockerError::Usage(_))));
+        assert!(matches!(docker.run(&args(&["exec", "web"])), Err(DockerError::Usage(_))));
+        assert!(matches!(docker.run(&args(&["start", "../../images"])), Err(DockerError::Usage(_))));
+        assert!(matches!(docker.run(&args(&["start", "web"])), Err(DockerError::Io(_))));
+    }
+}
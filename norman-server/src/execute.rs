@@ -0,0 +1,354 @@
+use std::{fmt, io};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use norman_protocol::{NormanPacket, Service, SHELLS};
+
+use crate::ThreadPool;
+
+/// What a command left behind once it finished.
+#[derive(PartialEq, Clone, Debug)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+/// How a request's command gets started.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Invocation {
+    /// Start the program directly with these arguments; nothing is interpreted.
+    Direct(Vec<String>),
+    /// Hand the command line to a shell with `-c`.
+    Shell { shell: String, command: String },
+}
+
+impl Invocation {
+    /// Work out how to run a request.
+    ///
+    /// Requests carrying an argv are started directly. A command string is only
+    /// given to a shell when a SHELL request names one, or failing that when the
+    /// server has a `default_shell`. Otherwise it is split into words, honouring
+    /// quotes but nothing else, and started directly.
+    pub fn from_packet(packet: &NormanPacket, default_shell: Option<&str>) -> Result<Invocation, String> {
+        let data = &packet.data;
+
+        if !data.argv.is_empty() {
+            if data.shell.is_some() {
+                return Err(String::from("A request can give an argv or a shell command, not both"));
+            }
+            return Ok(Invocation::Direct(data.argv.clone()));
+        }
+
+        let shell = match (&data.shell, packet.header.service == Service::SHELL) {
+            (Some(shell), true) => Some(shell.as_str()),
+            (Some(_), false) => return Err(format!("A shell can't be chosen for {} requests", packet.header.service)),
+            (None, true) => default_shell,
+            (None, false) => None,
+        };
+
+        match shell {
+            Some(shell) if !SHELLS.contains(&shell) => Err(format!("Unsupported shell \"{}\". Expected one of {}", shell, SHELLS.join(", "))),
+            Some(shell) => Ok(Invocation::Shell{shell: shell.to_string(), command: data.data.clone()}),
+            None => {
+                let words = shell_words::split(&data.data).map_err(|error| format!("Problem splitting command: {}", error))?;
+                match words.is_empty() {
+                    true => Err(String::from("No command given")),
+                    false => Ok(Invocation::Direct(words)),
+                }
+            },
+        }
+    }
+
+    /// The program that is started: the shell, or the first word.
+    pub fn program(&self) -> &str {
+        match self {
+            Invocation::Direct(argv) => &argv[0],
+            Invocation::Shell{shell, ..} => shell,
+        }
+    }
+}
+
+impl fmt::Display for Invocation {
+    /// The command as it would be typed at a prompt.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Invocation::Direct(argv) => write!(f, "{}", shell_words::join(argv)),
+            Invocation::Shell{command, ..} => write!(f, "{}", command),
+        }
+    }
+}
+
+/// Where, and as whom, a command runs. Unset fields are inherited from the server.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct RunAs {
+    pub working_dir: Option<String>,
+    /// Name of the user to run as. Switching user needs the server to run as root.
+    pub user: Option<String>,
+}
+
+/// Run a command and wait for it, capturing stdout and stderr separately.
+pub fn run_captured(invocation: &Invocation, run_as: &RunAs) -> io::Result<CommandOutput> {
+    let output = command(invocation, run_as)?
+        .stdin(Stdio::null())
+        .output()?;
+
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        exit_code: exit_code(output.status),
+    })
+}
+
+/// Work left running after its request has been answered: fire-and-forget
+/// commands and backend calls.
+///
+/// Only `limit` pieces of work may be running at once, so clients can't start
+/// processes or queue jobs on the pool without bound. Backend calls run on
+/// the pool like any other request.
+#[derive(Clone)]
+pub struct Background {
+    running: Arc<AtomicUsize>,
+    limit: usize,
+    pool: Arc<ThreadPool>,
+}
+
+/// A place among the background work, given back when dropped.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Background {
+    pub fn new(limit: usize, pool: Arc<ThreadPool>) -> Background {
+        Background{running: Arc::new(AtomicUsize::new(0)), limit, pool}
+    }
+
+    /// Take a place for a new piece of work, or say why there isn't one.
+    pub fn reserve(&self) -> Result<Slot, String> {
+        let running = self.running.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(Arc::clone(&self.running));
+        match running < self.limit {
+            true => Ok(slot),
+            false => Err(format!("Already running {} jobs in the background; try again once some finish", self.limit)),
+        }
+    }
+
+    /// The number of pieces of work running.
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Run `job` on the pool, keeping `slot` until it is done.
+    pub fn execute<F>(&self, slot: Slot, job: F)
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.pool.execute(move || {
+            let _slot = slot;
+            job();
+        });
+    }
+}
+
+/// Start a command in the background without waiting for it to finish.
+///
+/// The command's output is thrown away. A thread is left waiting on the child
+/// so it gets cleaned up when it exits, and `slot` is held until then.
+/// Returns the id of the new process.
+pub fn spawn_detached(invocation: &Invocation, run_as: &RunAs, slot: Slot) -> io::Result<u32> {
+    let mut child = command(invocation, run_as)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let id = child.id();
+
+    thread::spawn(move || {
+        let _ = child.wait();
+        drop(slot);
+    });
+
+    Ok(id)
+}
+
+fn command(invocation: &Invocation, run_as: &RunAs) -> io::Result<Command> {
+    let mut command = Command::new(invocation.program());
+    match invocation {
+        Invocation::Direct(argv) => command.args(&argv[1..]),
+        Invocation::Shell{command: line, ..} => command.arg("-c").arg(line),
+    };
+
+    if let Some(dir) = &run_as.working_dir {
+        command.current_dir(dir);
+    }
+    if let Some(name) = &run_as.user {
+        switch_user(&mut command, name)?;
+    }
+    Ok(command)
+}
+
+#[cfg(unix)]
+fn switch_user(command: &mut Command, name: &str) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let user = match nix::unistd::User::from_name(name) {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No user named {}", name))),
+        Err(error) => return Err(io::Error::from(error)),
+    };
+    //Root's supplementary groups are dropped by the standard library when the uid changes
+    command.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn switch_user(_command: &mut Command, name: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Can't run as {} on this platform", name)))
+}
+
+/// Lay rows out in left-aligned columns, the first row being the headings.
+pub(crate) fn format_table(rows: &[Vec<String>]) -> String {
+    let mut widths = Vec::new();
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let line = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect::<Vec<String>>().join("   ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// The process's exit code, or 128 plus the signal number if a signal killed it, as shells report it.
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => -1,
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use norman_protocol::{RequestType, Status, PROTOCOL_VERSION};
+
+    fn sh(command: &str) -> Invocation {
+        Invocation::Shell{shell: String::from("sh"), command: command.to_string()}
+    }
+
+    fn request(service: Service, command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, service, RequestType::REQUEST, Status::OK, String::from("None"), command.to_string(), false)
+    }
+
+    #[test]
+    fn captures_streams_and_exit_code() {
+        let output = run_captured(&sh("echo out; echo 'a | b' >&2; exit 3"), &RunAs::default()).unwrap();
+
+        assert_eq!(output, CommandOutput {
+            stdout: String::from("out\n"),
+            stderr: String::from("a | b\n"),
+            exit_code: 3,
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_signals_like_a_shell() {
+        assert_eq!(run_captured(&sh("kill -9 $$"), &RunAs::default()).unwrap().exit_code, 137);
+    }
+
+    #[test]
+    fn direct_arguments_are_not_interpreted() {
+        let invocation = Invocation::Direct(vec![String::from("echo"), String::from("$HOME; rm -rf /"), String::from("a | b")]);
+
+        assert_eq!(run_captured(&invocation, &RunAs::default()).unwrap().stdout, "$HOME; rm -rf / a | b\n");
+        assert!(run_captured(&Invocation::Direct(vec![String::from("no-such-norman-program")]), &RunAs::default()).is_err());
+    }
+
+    #[test]
+    fn choosing_an_invocation() {
+        let mut packet = request(Service::SHELL, "grep -r 'two words' /var/log | wc -l");
+        assert_eq!(Invocation::from_packet(&packet, None), Ok(Invocation::Direct(vec![
+            String::from("grep"), String::from("-r"), String::from("two words"), String::from("/var/log"), String::from("|"), String::from("wc"), String::from("-l"),
+        ])));
+        assert_eq!(Invocation::from_packet(&packet, Some("sh")), Ok(sh("grep -r 'two words' /var/log | wc -l")));
+
+        packet.data.shell = Some(String::from("bash"));
+        assert_eq!(Invocation::from_packet(&packet, None).unwrap().program(), "bash");
+        packet.data.shell = Some(String::from("python"));
+        assert!(Invocation::from_packet(&packet, None).is_err());
+
+        packet.data.shell = None;
+        packet.data.argv = vec![String::from("ls"), String::from("-la")];
+        assert_eq!(Invocation::from_packet(&packet, Some("sh")), Ok(Invocation::Direct(vec![String::from("ls"), String::from("-la")])));
+
+        let mut packet = request(Service::DOCKER, "ps");
+        assert_eq!(Invocation::from_packet(&packet, Some("sh")), Ok(Invocation::Direct(vec![String::from("ps")])));
+        packet.data.shell = Some(String::from("sh"));
+        assert!(Invocation::from_packet(&packet, None).is_err());
+
+        assert!(Invocation::from_packet(&request(Service::SHELL, "  "), None).is_err());
+        assert!(Invocation::from_packet(&request(Service::SHELL, "echo 'unclosed"), None).is_err());
+    }
+
+    #[test]
+    fn runs_in_working_dir() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let run_as = RunAs{working_dir: Some(dir.to_str().unwrap().to_string()), user: None};
+
+        assert_eq!(run_captured(&Invocation::Direct(vec![String::from("pwd"), String::from("-P")]), &run_as).unwrap().stdout.trim_end(), dir.to_str().unwrap());
+        assert!(run_captured(&sh("true"), &RunAs{working_dir: Some(String::from("/nonexistent")), user: None}).is_err());
+    }
+
+    #[test]
+    fn background_work_is_limited() {
+        let background = Background::new(2, Arc::new(ThreadPool::new(1)));
+
+        let first = background.reserve().unwrap();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        background.execute(first, move || {
+            let _ = released.recv();
+        });
+        spawn_detached(&sh("sleep 0.2"), &RunAs::default(), background.reserve().unwrap()).unwrap();
+        assert!(background.reserve().is_err());
+        assert_eq!(background.running(), 2);
+
+        //Places come back as the work finishes
+        drop(release);
+        let started = std::time::Instant::now();
+        while background.running() > 0 {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(background.reserve().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unknown_user() {
+        let run_as = RunAs{working_dir: None, user: Some(String::from("no-such-norman-user"))};
+
+        assert_eq!(run_captured(&sh("true"), &run_as).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,137 @@
+//! Just enough HTTP/1.1 to talk to the backends behind the DOCKER and AWS services.
+//!
+//! Every request is sent with `Connection: close`, so a response is everything
+//! read until the other end hangs up. Its body still has to be as long as
+//! `Content-Length` says, or end with the chunked terminator, so a connection
+//! that drops part way through isn't taken for a whole response.
+
+use std::io::{self, Read, Write};
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Parse everything read from the connection. `closed_cleanly` is false
+    /// when the other end hung up without saying it had finished, so a body
+    /// that doesn't give its own length can't be trusted to be whole.
+    fn parse(raw: &[u8], closed_cleanly: bool) -> Result<Response, String> {
+        let header_end = raw.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(|| String::from("incomplete headers"))?;
+        let head = String::from_utf8_lossy(&raw[..header_end]);
+        let body = &raw[header_end + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status = lines.next().and_then(|line| line.split_whitespace().nth(1)).and_then(|code| code.parse().ok()).ok_or_else(|| String::from("no status line"))?;
+
+        let (mut chunked, mut length) = (false, None);
+        for line in lines {
+            let line = line.to_ascii_lowercase();
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            match name {
+                "transfer-encoding" => chunked = value.contains("chunked"),
+                "content-length" => length = Some(value.parse::<usize>().map_err(|_| String::from("bad content length"))?),
+                _ => {},
+            }
+        }
+
+        let body = match (chunked, length) {
+            //These never have a body, whatever the headers say
+            _ if status == 204 || status == 304 => Vec::new(),
+            (true, _) => dechunk(body)?,
+            (false, Some(length)) if body.len() < length => return Err(format!("truncated body: got {} of {} bytes", body.len(), length)),
+            (false, Some(length)) => body[..length].to_vec(),
+            (false, None) if !closed_cleanly => return Err(String::from("connection dropped before the end of the body")),
+            (false, None) => body.to_vec(),
+        };
+        Ok(Response{status, body})
+    }
+}
+
+/// Why an exchange failed: the connection broke, or the reply wasn't HTTP.
+pub enum HttpError {
+    Io(io::Error),
+    BadResponse(String),
+}
+
+impl From<io::Error> for HttpError {
+    fn from(error: io::Error) -> HttpError {
+        HttpError::Io(error)
+    }
+}
+
+/// Send a request and read back the whole response.
+///
+/// `headers` shouldn't include `Host`, `Content-Length` or `Connection`; those are added here.
+pub fn exchange<S: Read + Write>(stream: &mut S, method: &str, host: &str, path: &str, headers: &[(&str, String)], body: &[u8]) -> Result<Response, HttpError> {
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if method != "GET" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut raw = Vec::new();
+    let closed_cleanly = match stream.read_to_end(&mut raw) {
+        Ok(_) => true,
+        //TLS servers often hang up without a close_notify, which is fine if the body says where it ends
+        Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => false,
+        Err(error) => return Err(HttpError::Io(error)),
+    };
+    Response::parse(&raw, closed_cleanly).map_err(HttpError::BadResponse)
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n").ok_or_else(|| String::from("truncated chunk"))?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16).map_err(|_| String::from("bad chunk size"))?;
+        body = &body[line_end + 2..];
+
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size {
+            return Err(String::from("truncated chunk"));
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = body[size..].strip_prefix(b"\r\n").unwrap_or(&body[size..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_must_be_whole() {
+        let response = Response::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", false).unwrap();
+        assert_eq!(response.body, b"hello");
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel", true).is_err());
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel", false).is_err());
+
+        let response = Response::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n", false).unwrap();
+        assert_eq!(response.body, b"hello");
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n", false).is_err());
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", true).is_err());
+
+        assert_eq!(Response::parse(b"HTTP/1.1 200 OK\r\n\r\nhello", true).unwrap().body, b"hello");
+        assert!(Response::parse(b"HTTP/1.1 200 OK\r\n\r\nhello", false).is_err());
+        assert!(Response::parse(b"HTTP/1.1 204 No Content\r\n\r\n", false).unwrap().body.is_empty());
+    }
+}
@@ -1,25 +1,387 @@
+use std::collections::BTreeMap;
+use std::{fmt, fs};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Mutex, Arc};
 
+use norman_protocol::{Capabilities, ClientKey, Health, PresharedKey, Service, PROTOCOL_VERSION, SHELLS, SUPPORTED_ENCRYPTION, DEFAULT_FRAGMENT_SIZE, DEFAULT_MAX_PACKET_SIZE, FRAME_HEADER_LEN};
+
+mod aws;
+mod config;
+mod docker;
+mod execute;
+mod http;
+#[macro_use]
+mod log;
+mod policy;
+mod service;
+mod tls;
+
+pub use aws::{Aws, AwsCredentials, AwsError, AwsService, Endpoint, AWS_USAGE, DEFAULT_AWS_REGION};
+pub use config::{ClientSettings, ConfigFile};
+pub use docker::{Docker, DockerError, DEFAULT_DOCKER_SOCKET, DOCKER_USAGE};
+pub use execute::{run_captured, spawn_detached, Background, CommandOutput, Invocation, RunAs, Slot};
+pub use log::{log_enabled, set_log_level, LogLevel};
+pub use policy::Policy;
+pub use service::{accepted_response, capabilities_response, dispatch, error_response, health_response, into_fragments, output_response, Request, ServiceHandler, ServiceRegistry, ShellService};
+pub use tls::{Connection, ReadHalf, TlsOptions, WriteHalf};
+
+pub const DEFAULT_PORT: u16 = 7878;
+pub const DEFAULT_THREAD_COUNT: usize = 4;
+/// Seconds a connection may sit idle before it is closed.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+pub const DEFAULT_MAX_BACKGROUND: usize = 64;
+
+/// The server program and version, as reported to clients.
+pub const SOFTWARE: &str = concat!("norman-server ", env!("CARGO_PKG_VERSION"));
+
+pub const USAGE: &str = "Usage: norman-server [options] [thread count]
+
+Options:
+    -c, --config <file>            Read settings from a TOML file. Flags take precedence over the file
+    -b, --bind <addr>              Address to listen on, with or without a port. May be repeated (default 127.0.0.1)
+    -p, --port <port>              Port for addresses that don't give one (default 7878)
+    -t, --threads <count>          Number of requests to run at once (default 4)
+        --callback-port <port>     Legacy mode: send responses on a new connection to this port on the client
+    -s, --allow-service <name>     Only accept requests for this service. May be repeated (default all)
+    -l, --log-level <level>        error, warn, info or debug (default info)
+        --max-packet-size <bytes>  Largest request the server will read (default 16777216)
+        --fragment-size <bytes>    Most output sent in a single response packet (default 65536)
+        --default-shell <shell>    Run SHELL commands that don't choose a shell through sh, bash or zsh.
+                                   Without it they are split into words and run directly
+    -k, --key-file <file>          File holding a 32 byte pre-shared key as hex, e.g. from `openssl rand -hex 32`
+        --require-encryption       Refuse requests that aren't encrypted with the pre-shared key
+        --tls-cert <file>          Serve over TLS with this PEM certificate chain
+        --tls-key <file>           PEM private key for the TLS certificate
+        --tls-client-ca <file>     Require clients to present a certificate signed by this PEM CA
+        --docker-socket <path>     Unix socket of the Docker engine for DOCKER requests (default /var/run/docker.sock)
+        --aws-region <region>      Region AWS requests manage instances in (default us-east-1)
+        --aws-endpoint <url>       EC2 API to send AWS requests to (default the region's public endpoint).
+                                   Credentials come from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+        --name <name>              Name to give clients that ask for the server's capabilities (default the hostname)
+        --idle-timeout <secs>      Close connections that send nothing and have nothing running for this long (default 300)
+        --max-connections <count>  Most connections to serve at once. Any more are closed straight away (default 256)
+        --max-in-flight <count>    Most requests one connection may have running or waiting at once. Reading
+                                   from it pauses until replies go out (default 32)
+        --max-background <count>   Most fire-and-forget requests left running at once. Any more are turned
+                                   away (default 64)
+    -h, --help                     Print this message";
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum UsageError {
+    HelpRequested,
+    Invalid(String),
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsageError::HelpRequested => write!(f, "{}", USAGE),
+            UsageError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
 //Parse User Input
+#[derive(PartialEq, Clone, Debug)]
 pub struct UserOptions {
+    /// Addresses to listen on. Those without a port use `port`.
+    pub bind: Vec<String>,
+    pub port: u16,
     pub thread_count: usize,
+    /// Legacy mode: dial back to the client on this port instead of replying on the request connection.
+    pub callback_port: Option<u16>,
+    /// Services requests may use, or every registered service when unset.
+    /// Checked against the registry once its handlers are in, by `ServiceRegistry::restrict`.
+    pub allowed_services: Option<Vec<Service>>,
+    pub log_level: LogLevel,
+    pub max_packet_size: usize,
+    pub fragment_size: usize,
+    /// Shell for SHELL requests that send a command line without choosing one.
+    pub default_shell: Option<String>,
+    /// Key for decrypting requests and encrypting their responses.
+    pub key: Option<PresharedKey>,
+    pub require_encryption: bool,
+    /// Serve over TLS instead of plain TCP.
+    pub tls: Option<TlsOptions>,
+    /// The clients allowed to send requests, by id. When empty, requests needn't be signed.
+    pub clients: BTreeMap<String, Client>,
+    /// Unix socket DOCKER requests are sent to the Docker engine over.
+    pub docker_socket: String,
+    pub aws_region: String,
+    pub aws_endpoint: Endpoint,
+    /// Name the server goes by in its capabilities.
+    pub server_name: String,
+    /// How long a connection may sit with nothing sent and nothing running, and how long a write may take.
+    pub idle_timeout: Duration,
+    pub max_connections: usize,
+    /// Requests one connection may have unanswered before the server stops reading from it.
+    pub max_in_flight: usize,
+    /// Fire-and-forget requests that may be left running at once.
+    pub max_background: usize,
+}
+
+/// A client the server knows, with what it may do.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Client {
+    pub key: ClientKey,
+    pub policy: Policy,
+    /// Common name of the certificate the client's machine must present.
+    pub certificate: Option<String>,
+}
+
+impl Client {
+    /// Whether a request signed as this client may come from a connection
+    /// that presented a certificate named `presented`, and why not if it can't.
+    pub fn check_certificate(&self, presented: Option<&str>) -> Result<(), String> {
+        match (&self.certificate, presented) {
+            (None, _) => Ok(()),
+            (Some(expected), Some(presented)) if expected == presented => Ok(()),
+            (Some(expected), Some(presented)) => Err(format!("Signed on a machine named \"{}\" but must come from \"{}\"", presented, expected)),
+            (Some(expected), None) => Err(format!("Signed without a certificate but must come from \"{}\"", expected)),
+        }
+    }
 }
 
 impl UserOptions {
-    pub fn new(mut args: std::env::Args) -> Result<UserOptions, &'static str> {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<UserOptions, UsageError> {
         args.next();
-        let thread_count = match args.next() {
-            Some(arg) => arg,
-            None => return Err("No thread count provided. \n This is the number of threads the application should create"),
+
+        let mut flags = ConfigFile::default();
+        let mut config_path = None;
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(UsageError::HelpRequested),
+                "-c" | "--config" => config_path = Some(option_value(&mut args, &arg)?),
+                "-b" | "--bind" => flags.bind.get_or_insert_with(Vec::new).push(option_value(&mut args, &arg)?),
+                "-p" | "--port" => flags.port = Some(parse_number(&option_value(&mut args, &arg)?, "Port")?),
+                "-t" | "--threads" => flags.threads = Some(parse_number(&option_value(&mut args, &arg)?, "Thread count")?),
+                "--callback-port" => flags.callback_port = Some(parse_number(&option_value(&mut args, &arg)?, "Callback port")?),
+                "-s" | "--allow-service" => flags.allowed_services.get_or_insert_with(Vec::new).push(option_value(&mut args, &arg)?),
+                "-l" | "--log-level" => flags.log_level = Some(option_value(&mut args, &arg)?.parse().map_err(UsageError::Invalid)?),
+                "--max-packet-size" => flags.max_packet_size = Some(parse_number(&option_value(&mut args, &arg)?, "Max packet size")?),
+                "--fragment-size" => flags.fragment_size = Some(parse_number(&option_value(&mut args, &arg)?, "Fragment size")?),
+                "--default-shell" => flags.default_shell = Some(option_value(&mut args, &arg)?),
+                "-k" | "--key-file" => flags.key_file = Some(option_value(&mut args, &arg)?),
+                "--require-encryption" => flags.require_encryption = Some(true),
+                "--tls-cert" => flags.tls_cert = Some(option_value(&mut args, &arg)?),
+                "--tls-key" => flags.tls_key = Some(option_value(&mut args, &arg)?),
+                "--tls-client-ca" => flags.tls_client_ca = Some(option_value(&mut args, &arg)?),
+                "--docker-socket" => flags.docker_socket = Some(option_value(&mut args, &arg)?),
+                "--aws-region" => flags.aws_region = Some(option_value(&mut args, &arg)?),
+                "--aws-endpoint" => flags.aws_endpoint = Some(option_value(&mut args, &arg)?),
+                "--name" => flags.server_name = Some(option_value(&mut args, &arg)?),
+                "--idle-timeout" => flags.idle_timeout = Some(parse_number(&option_value(&mut args, &arg)?, "Idle timeout")?),
+                "--max-connections" => flags.max_connections = Some(parse_number(&option_value(&mut args, &arg)?, "Max connections")?),
+                "--max-in-flight" => flags.max_in_flight = Some(parse_number(&option_value(&mut args, &arg)?, "Max in flight")?),
+                "--max-background" => flags.max_background = Some(parse_number(&option_value(&mut args, &arg)?, "Max background")?),
+                _ if arg.starts_with('-') => return Err(UsageError::Invalid(format!("Unknown option \"{}\"", arg))),
+                _ => positional.push(arg),
+            }
+        }
+
+        //The old positional form: norman-server <thread count> [callback port]
+        let mut positional = positional.into_iter();
+        if let Some(arg) = positional.next() {
+            flags.threads = flags.threads.or(Some(parse_number(&arg, "Thread count")?));
+        }
+        if let Some(arg) = positional.next() {
+            flags.callback_port = flags.callback_port.or(Some(parse_number(&arg, "Callback port")?));
+        }
+        if let Some(arg) = positional.next() {
+            return Err(UsageError::Invalid(format!("Unexpected argument \"{}\"", arg)));
+        }
+
+        let config = match config_path {
+            Some(path) => flags.or(ConfigFile::load(&path).map_err(UsageError::Invalid)?),
+            None => flags,
+        };
+
+        UserOptions::from_config(config)
+    }
+
+    /// Fill in defaults for anything the config leaves unset and check the result makes sense.
+    pub fn from_config(config: ConfigFile) -> Result<UserOptions, UsageError> {
+        //Whether the services exist is only known once their handlers are registered
+        let allowed_services = match config.allowed_services {
+            Some(names) => Some(names.iter().map(|name| {
+                Service::new(name).map_err(|error| UsageError::Invalid(format!("Bad service name \"{}\": {}", name, error)))
+            }).collect::<Result<Vec<Service>, UsageError>>()?),
+            None => None,
+        };
+
+        let key = match config.key_file {
+            Some(path) => {
+                let contents = fs::read_to_string(&path).map_err(|error| UsageError::Invalid(format!("Problem reading key file {}: {}", path, error)))?;
+                Some(PresharedKey::from_hex(&contents).map_err(|error| UsageError::Invalid(format!("Problem parsing key file {}: {}", path, error)))?)
+            },
+            None => None,
+        };
+
+        let tls = match (config.tls_cert, config.tls_key, config.tls_client_ca) {
+            (Some(cert), Some(key), client_ca) => Some(TlsOptions{cert, key, client_ca}),
+            (None, None, None) => None,
+            (_, _, Some(_)) => return Err(UsageError::Invalid(String::from("Checking client certificates needs a TLS certificate and key"))),
+            _ => return Err(UsageError::Invalid(String::from("TLS needs both a certificate and a key"))),
+        };
+
+        let aws_region = config.aws_region.unwrap_or_else(|| String::from(DEFAULT_AWS_REGION));
+        let aws_endpoint = match config.aws_endpoint {
+            Some(url) => Endpoint::parse(&url).map_err(UsageError::Invalid)?,
+            None => Endpoint::for_region(&aws_region),
         };
-        
-        let thread_count = thread_count.trim().parse().expect("Thread count must be a number");
-        
-        Ok(UserOptions{thread_count})
+
+        let checks_certificates = matches!(&tls, Some(TlsOptions{client_ca: Some(_), ..}));
+        let mut clients = BTreeMap::new();
+        for (client_id, settings) in config.clients.unwrap_or_default() {
+            if client_id.is_empty() || client_id.contains(&[':', '|'][..]) {
+                return Err(UsageError::Invalid(format!("Client id \"{}\" must be non-empty and free of ':' and '|'", client_id)));
+            }
+            let key = ClientKey::from_hex(&settings.key).map_err(|error| UsageError::Invalid(format!("Problem with key for client {}: {}", client_id, error)))?;
+            let policy = Policy::from_settings(&settings).map_err(|error| UsageError::Invalid(format!("Problem with policy for client {}: {}", client_id, error)))?;
+            if settings.certificate.is_some() && !checks_certificates {
+                return Err(UsageError::Invalid(format!("Client {} names a certificate, which needs --tls-client-ca", client_id)));
+            }
+            clients.insert(client_id, Client{key, policy, certificate: settings.certificate});
+        }
+
+        let options = UserOptions {
+            bind: config.bind.unwrap_or_else(|| vec![String::from("127.0.0.1")]),
+            port: config.port.unwrap_or(DEFAULT_PORT),
+            thread_count: config.threads.unwrap_or(DEFAULT_THREAD_COUNT),
+            callback_port: config.callback_port,
+            allowed_services,
+            log_level: config.log_level.unwrap_or(LogLevel::Info),
+            max_packet_size: config.max_packet_size.unwrap_or(DEFAULT_MAX_PACKET_SIZE),
+            fragment_size: config.fragment_size.unwrap_or(DEFAULT_FRAGMENT_SIZE),
+            default_shell: config.default_shell,
+            key,
+            require_encryption: config.require_encryption.unwrap_or(false),
+            tls,
+            clients,
+            docker_socket: config.docker_socket.unwrap_or_else(|| String::from(DEFAULT_DOCKER_SOCKET)),
+            aws_region,
+            aws_endpoint,
+            server_name: config.server_name.unwrap_or_else(hostname),
+            idle_timeout: Duration::from_secs(config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT)),
+            max_connections: config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            max_in_flight: config.max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+            max_background: config.max_background.unwrap_or(DEFAULT_MAX_BACKGROUND),
+        };
+
+        if options.bind.is_empty() {
+            return Err(UsageError::Invalid(String::from("At least one bind address is needed")));
+        }
+        if options.thread_count == 0 {
+            return Err(UsageError::Invalid(String::from("Thread count must be at least 1")));
+        }
+        if options.max_packet_size <= FRAME_HEADER_LEN {
+            return Err(UsageError::Invalid(format!("Max packet size must be more than {} bytes", FRAME_HEADER_LEN)));
+        }
+        if options.fragment_size == 0 {
+            return Err(UsageError::Invalid(String::from("Fragment size must be at least 1 byte")));
+        }
+        if let Some(shell) = &options.default_shell {
+            if !SHELLS.contains(&shell.as_str()) {
+                return Err(UsageError::Invalid(format!("Unsupported shell \"{}\". Expected one of {}", shell, SHELLS.join(", "))));
+            }
+        }
+        if options.require_encryption && options.key.is_none() {
+            return Err(UsageError::Invalid(String::from("Requiring encryption needs a key file")));
+        }
+        if options.tls.is_some() && options.callback_port.is_some() {
+            return Err(UsageError::Invalid(String::from("The legacy callback can't be sent over TLS")));
+        }
+        if options.idle_timeout.as_secs() == 0 {
+            return Err(UsageError::Invalid(String::from("Idle timeout must be at least 1 second")));
+        }
+        if options.max_connections == 0 {
+            return Err(UsageError::Invalid(String::from("Max connections must be at least 1")));
+        }
+        if options.max_in_flight == 0 {
+            return Err(UsageError::Invalid(String::from("Max in flight must be at least 1")));
+        }
+        if options.max_background == 0 {
+            return Err(UsageError::Invalid(String::from("Max background must be at least 1")));
+        }
+        if options.server_name.is_empty() || options.server_name.contains(&['\n', '|'][..]) {
+            return Err(UsageError::Invalid(format!("Server name \"{}\" must be non-empty and free of newlines and '|'", options.server_name)));
+        }
+
+        Ok(options)
+    }
+
+    /// The `host:port` strings to bind listeners to.
+    pub fn bind_addresses(&self) -> Vec<String> {
+        self.bind.iter().map(|address| {
+            if address.parse::<SocketAddr>().is_ok() {
+                address.clone()
+            } else if let Ok(ip) = address.parse::<IpAddr>() {
+                SocketAddr::new(ip, self.port).to_string()
+            } else if address.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+                address.clone()
+            } else {
+                format!("{}:{}", address, self.port)
+            }
+        }).collect()
+    }
+
+    /// What the server tells clients that send a CAPABILITIES request.
+    pub fn capabilities(&self, services: &ServiceRegistry) -> Capabilities {
+        Capabilities {
+            version: String::from(PROTOCOL_VERSION),
+            server: self.server_name.clone(),
+            software: String::from(SOFTWARE),
+            services: services.names().filter_map(|name| Service::new(name).ok()).collect(),
+            encryption: SUPPORTED_ENCRYPTION.iter().map(|name| name.to_string()).collect(),
+            max_packet_size: self.max_packet_size,
+        }
+    }
+}
+
+/// What the server answers a TEST request with.
+pub fn health(uptime: Duration, load: &PoolLoad) -> Health {
+    Health {
+        version: String::from(PROTOCOL_VERSION),
+        software: String::from(SOFTWARE),
+        uptime,
+        workers: load.size(),
+        busy_workers: load.busy(),
+        queued: load.queued(),
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    match nix::unistd::gethostname() {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => String::from("norman"),
+    }
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("norman"))
+}
+
+fn option_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, UsageError> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => Err(UsageError::Invalid(format!("{} needs a value", option))),
     }
 }
 
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, UsageError> {
+    value.trim().parse().map_err(|_| UsageError::Invalid(format!("{} must be a number, not \"{}\"", name, value)))
+}
+
 // ThreadPool System
 enum Message {
     NewJob(Job),
@@ -29,6 +391,32 @@ enum Message {
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message>,
+    load: PoolLoad,
+}
+
+/// A view of how busy a ThreadPool is, which can be handed to its own jobs.
+#[derive(Clone, Debug)]
+pub struct PoolLoad {
+    size: usize,
+    busy: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl PoolLoad {
+    /// The number of threads in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of threads running a job.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// The number of jobs waiting for a thread.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -48,18 +436,29 @@ impl ThreadPool {
 
         let receiver = Arc::new(Mutex::new(receiver));
 
+        let load = PoolLoad {
+            size,
+            busy: Arc::new(AtomicUsize::new(0)),
+            queued: Arc::new(AtomicUsize::new(0)),
+        };
+
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), load.clone()));
         }
 
         ThreadPool {
             workers,
             sender,
+            load,
         }
     }
 
+    pub fn load(&self) -> PoolLoad {
+        self.load.clone()
+    }
+
     /// Run a function on the next available thread in the pool.
     /// 
     /// f is the function you want to run.
@@ -69,22 +468,23 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
+        self.load.queued.fetch_add(1, Ordering::SeqCst);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        log!(LogLevel::Info, "Sending terminate message to all workers.");
 
         for _ in &mut self.workers {
             self.sender.send(Message::Terminate).unwrap();
         } 
 
-        println!("Shutting down all workers.");
+        log!(LogLevel::Info, "Shutting down all workers.");
 
         for worker in &mut self.workers {
-            println!("Shuuting down worker {}", worker.id);
+            log!(LogLevel::Debug, "Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -99,7 +499,7 @@ pub struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) ->
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>, load: PoolLoad) ->
         Worker {
 
         let thread = thread::spawn(move ||{
@@ -108,12 +508,19 @@ impl Worker {
 
                 match message {
                     Message::NewJob(job) => {
-                        println!("Worker {} got a job; executing.", id);
-
-                        job();
+                        log!(LogLevel::Debug, "Worker {} got a job; executing.", id);
+
+                        load.busy.fetch_add(1, Ordering::SeqCst);
+                        load.queued.fetch_sub(1, Ordering::SeqCst);
+                        //A job that panics mustn't take the worker down with it
+                        let finished = panic::catch_unwind(AssertUnwindSafe(job));
+                        load.busy.fetch_sub(1, Ordering::SeqCst);
+                        if finished.is_err() {
+                            log!(LogLevel::Error, "Worker {} caught a panicking job", id);
+                        }
                     },
                     Message::Terminate => {
-                        println!("Worker {} was told to terminate.", id);
+                        log!(LogLevel::Debug, "Worker {} was told to terminate.", id);
 
                         break;
                     },
@@ -128,225 +535,227 @@ impl Worker {
     }
 }
 
-//Packet Structure
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[derive(PartialEq, Clone, Debug)]
-pub enum Service {
-    SHELL,
-    DOCKER,
-    AWS,
-    UNKNOWN,
-}
+    fn parse(args: &[&str]) -> Result<UserOptions, UsageError> {
+        UserOptions::new(std::iter::once("norman-server").chain(args.iter().copied()).map(String::from))
+    }
 
-#[derive(PartialEq, Clone, Debug)]
-pub enum RequestType{
-    REQUEST,
-    RETURN,
-    TEST,
-    ERROR,
-}
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+
+        assert_eq!(options.bind_addresses(), vec![String::from("127.0.0.1:7878")]);
+        assert_eq!(options.thread_count, DEFAULT_THREAD_COUNT);
+        assert_eq!(options.callback_port, None);
+        assert_eq!(options.allowed_services, None);
+        assert_eq!(options.log_level, LogLevel::Info);
+        assert_eq!(options.default_shell, None);
+        assert_eq!(options.key, None);
+        assert!(!options.require_encryption);
+        assert_eq!(options.tls, None);
+        assert!(options.clients.is_empty());
+        assert_eq!(options.docker_socket, DEFAULT_DOCKER_SOCKET);
+        assert_eq!(options.aws_endpoint.to_string(), "https://ec2.us-east-1.amazonaws.com/");
+        assert!(!options.server_name.is_empty());
+        assert_eq!(options.idle_timeout, Duration::from_secs(DEFAULT_IDLE_TIMEOUT));
+        assert_eq!(options.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(options.max_in_flight, DEFAULT_MAX_IN_FLIGHT);
+        assert_eq!(options.max_background, DEFAULT_MAX_BACKGROUND);
+    }
 
-#[derive(PartialEq, Clone, Debug)]
-pub enum Status {
-    FINE{code: i32},
-    ERROR{code: i32},
-    TEST{code:i32},
-    MALFORMED{code:i32},
-}
+    #[test]
+    fn flags() {
+        let options = parse(&["-b", "0.0.0.0", "--bind", "::1", "-b", "[::1]:9001", "-b", "localhost", "-p", "9000", "--threads", "8", "-s", "shell", "-l", "debug", "--max-packet-size", "4096", "--default-shell", "bash", "--docker-socket", "/tmp/docker.sock", "--aws-region", "eu-west-1", "--name", "build-01", "--idle-timeout", "60", "--max-connections", "16", "--max-in-flight", "4", "--max-background", "8"]).unwrap();
+
+        assert_eq!(options.bind_addresses(), vec![
+            String::from("0.0.0.0:9000"),
+            String::from("[::1]:9000"),
+            String::from("[::1]:9001"),
+            String::from("localhost:9000"),
+        ]);
+        assert_eq!(options.thread_count, 8);
+        assert_eq!(options.allowed_services, Some(vec![Service::SHELL]));
+        assert_eq!(options.log_level, LogLevel::Debug);
+        assert_eq!(options.max_packet_size, 4096);
+        assert_eq!(options.default_shell, Some(String::from("bash")));
+        assert_eq!(options.docker_socket, "/tmp/docker.sock");
+        assert_eq!(options.aws_region, "eu-west-1");
+        assert_eq!(options.aws_endpoint, Endpoint::for_region("eu-west-1"));
+        assert_eq!(options.server_name, "build-01");
+        assert_eq!(options.idle_timeout, Duration::from_secs(60));
+        assert_eq!(options.max_connections, 16);
+        assert_eq!(options.max_in_flight, 4);
+        assert_eq!(options.max_background, 8);
+        assert!(parse(&["--idle-timeout", "0"]).is_err());
+        assert!(parse(&["--max-connections", "0"]).is_err());
+        assert!(parse(&["--max-in-flight", "0"]).is_err());
+        assert!(parse(&["--max-background", "0"]).is_err());
+        assert_eq!(parse(&["--aws-endpoint", "http://localhost:5000"]).unwrap().aws_endpoint.to_string(), "http://localhost:5000/");
+        assert!(parse(&["--aws-endpoint", "localhost:5000"]).is_err());
+    }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Header {
-    pub version: String, //Format NORMAN/<ver>
-    pub return_output: bool,
-    pub service: Service,
-}
+    #[test]
+    fn pool_load() {
+        let pool = ThreadPool::new(2);
+        let load = pool.load();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+
+        for _ in 0..3 {
+            let (started_tx, release_rx) = (started_tx.clone(), Arc::clone(&release_rx));
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+            });
+        }
+        //Both workers hold a job that waits to be released, leaving the third queued
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Metadata {
-    pub req_type: RequestType,
-    pub status: Status,
-    pub uid: i32,
-}
+        let report = health(Duration::from_secs(90), &load);
+        assert_eq!((report.workers, report.busy_workers, report.queued), (2, 2, 1));
+        assert_eq!(report.uptime, Duration::from_secs(90));
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Encryption {
-    pub encoding_type: String,
-    pub key: String,
-}
+        drop(release_tx);
+        drop(pool);
+        assert_eq!((load.busy(), load.queued()), (0, 0));
+    }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Data {
-    pub data: String,
-}
+    #[test]
+    fn panicking_jobs_keep_their_worker() {
+        let pool = ThreadPool::new(2);
+        let load = pool.load();
+        for _ in 0..4 {
+            pool.execute(|| panic!("handler blew up"));
+        }
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct Terminator {
-    pub multi_packet: bool,
-    pub term_string: String,
-}
+        //Both workers are still there to hold a job each at the same time
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        for _ in 0..2 {
+            let (started_tx, release_rx) = (started_tx.clone(), Arc::clone(&release_rx));
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+            });
+        }
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((load.busy(), load.queued()), (2, 0));
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct NormanPacket {
-    pub header: Header,
-    pub meta: Metadata,
-    pub encryption: Encryption,
-    pub data: Data,
-    pub terminator: Terminator, //Should be NORMAN/END
-}
+        drop(release_tx);
+        drop(pool);
+        assert_eq!((load.busy(), load.queued()), (0, 0));
+    }
 
-impl NormanPacket {
-    pub fn new (ver: String, ret_out: bool, service: Service, req_type: RequestType, status: Status, enc_type: String, data: String, multi_packet: bool) -> NormanPacket {
-        NormanPacket {
-            header: Header {
-                version: ver,
-                return_output: ret_out,
-                service: service,
-            },
-            meta: Metadata {
-                req_type: req_type,
-                status: status,
-                uid: 0,
-            },
-            encryption: Encryption {
-                encoding_type: enc_type,
-                key: String::from(" "),
-            },
-            data: Data {
-                data,
-            },
-            terminator: Terminator {
-                multi_packet: multi_packet,
-                term_string: String::from("NORMAN/END"),
-            },
-        }
+    #[test]
+    fn capabilities() {
+        let options = parse(&["-s", "shell", "-s", "aws", "--max-packet-size", "4096", "--name", "build-01"]).unwrap();
+        let mut services = ServiceRegistry::from_options(&options);
+        services.restrict(options.allowed_services.as_deref()).unwrap();
+        let capabilities = options.capabilities(&services);
+
+        assert_eq!(capabilities.version, PROTOCOL_VERSION);
+        assert_eq!(capabilities.server, "build-01");
+        assert!(capabilities.software.starts_with("norman-server "));
+        assert_eq!(capabilities.services, vec![Service::AWS, Service::SHELL]);
+        assert_eq!(capabilities.encryption, SUPPORTED_ENCRYPTION);
+        assert_eq!(capabilities.max_packet_size, 4096);
+
+        assert!(parse(&["--name", "build|01"]).is_err());
     }
 
-    pub fn as_string(&self) -> String {
-        let mut packet_string: String = String::new();
-
-        //Concatenate Header
-        packet_string = packet_string + 
-            //Version
-            &self.header.version + "|" +
-            //Return Flag
-            match &self.header.return_output{
-                true => "true",
-                false => "false",
-            } + "|" +
-            //Target Service
-            match &self.header.service {
-                Service::AWS => "AWS",
-                Service::DOCKER => "DOCKER",
-                Service::SHELL => "SHELL",
-                Service::UNKNOWN => "UNKNOWN"
-            } + "|";
-        
-        //Concatenate Metadata
-        packet_string = packet_string + 
-            //Type of packet
-            match &self.meta.req_type {
-                RequestType::REQUEST => "REQUEST",
-                RequestType::RETURN => "RETURN",
-                RequestType::TEST => "TEST",
-                RequestType::ERROR => "ERROR",
-            } + "|" +
-            //Status of packet
-            match &self.meta.status {
-                Status::FINE{code: 200} => "200 OK",
-                Status::ERROR{code: 500} => "500 ERR",
-                Status::TEST{code: 100} => "100 TEST",
-                _ => "505 MALFORMED"
-            } + "|" +
-            //Packet ID
-            &self.meta.uid.to_string()  + "|";
-        //Concatenate Encryption
-            packet_string = packet_string +
-            //Encryption Type
-            &self.encryption.encoding_type + "|" +
-            &self.encryption.key + "|";
-        //Concatenate Data
-        packet_string = packet_string +
-            //Packet Data
-            &self.data.data + "|";
-
-        //Concatenate Terminator
-        packet_string = packet_string +
-            //Concatenated packet flag
-            match &self.terminator.multi_packet{
-                true => "true",
-                false => "false",
-            } + "|" +
-            //Packet terminator
-            &self.terminator.term_string;
-
-        packet_string
+    #[test]
+    fn clients_from_config() {
+        let clients = |id: &str, key: String| {
+            let settings = ClientSettings{key, certificate: None, services: None, executables: None, allow: None, deny: None, allow_shell: None, working_dir: None, user: None};
+            Some(vec![(String::from(id), settings)].into_iter().collect())
+        };
+        let mut config = ConfigFile {clients: clients("alice", "ab".repeat(16)), ..ConfigFile::default()};
+
+        let options = UserOptions::from_config(config.clone()).unwrap();
+        assert_eq!(options.clients["alice"].key, ClientKey::from_hex(&"ab".repeat(16)).unwrap());
+        assert_eq!(options.clients["alice"].policy, Policy::default());
+
+        config.clients = clients("alice", String::from("abcd"));
+        assert!(matches!(UserOptions::from_config(config.clone()), Err(UsageError::Invalid(_))));
+
+        config.clients = clients("al:ice", "ab".repeat(16));
+        assert!(matches!(UserOptions::from_config(config), Err(UsageError::Invalid(_))));
     }
 
-    pub fn from_string (packet_string: String) -> NormanPacket {
-        let mut packet_components = packet_string.split("|");
-
-        let version: String;
-        let return_output: bool;
-        let service: Service;
-        
-        let req_type: RequestType;
-        let status: Status;
-        
-        let encoding_type: String;
-        
-        let data: String;
-        
-        let multi_packet: bool;
-        
-        if packet_components.clone().count() == 11 {
-            version = packet_components.next().unwrap().to_string();
-            return_output = match packet_components.next().unwrap() {
-                "true" => true,
-                "false" => false,
-                _ => true
-            };
-            service = match packet_components.next().unwrap() {
-                "SHELL" => Service::SHELL,
-                "AWS" => Service::AWS,
-                "DOCKER" => Service::DOCKER,
-                _ => Service::UNKNOWN,
-            };
-
-            req_type = match packet_components.next().unwrap() {
-                "REQUEST" => RequestType::REQUEST,
-                "RETURN" => RequestType::RETURN,
-                "TEST" => RequestType::TEST,
-                _ => RequestType::ERROR,
-            };
-            status = match packet_components.next().unwrap() {
-                "200 OK" => Status::FINE{code: 200},
-                "500 ERR" => Status::ERROR{code: 500},
-                "100 TEST" => Status::TEST{code: 100},
-                _ => Status::MALFORMED{code: 505},
-            };
-
-            packet_components.next();
-
-            encoding_type = packet_components.next().unwrap().to_string();
-
-            packet_components.next();
-
-            data = packet_components.next().unwrap().to_string();
-
-            multi_packet = match packet_components.next().unwrap() {
-                "true" => true,
-                "false" => false,
-                _ => false,
-            };
-        } else {
-            let component_count = packet_components.clone().count();
-            let mut panic_string = String::new();
-            for component in packet_components {
-                panic_string = format!("{}{}{}", panic_string, component, String::from(","));
-            }
-            panic!("Malformed packet! Expeceted 9 components but found {}. Packet read {}", component_count, panic_string);
-        }
+    #[test]
+    fn clients_tied_to_certificates() {
+        let settings = ClientSettings{key: "ab".repeat(16), certificate: Some(String::from("alice-laptop")), services: None, executables: None, allow: None, deny: None, allow_shell: None, working_dir: None, user: None};
+        let mut config = ConfigFile {clients: Some(vec![(String::from("alice"), settings)].into_iter().collect()), ..ConfigFile::default()};
+        assert!(matches!(UserOptions::from_config(config.clone()), Err(UsageError::Invalid(_))));
+
+        config.tls_cert = Some(String::from("server.pem"));
+        config.tls_key = Some(String::from("server.key"));
+        config.tls_client_ca = Some(String::from("ca.pem"));
+        let alice = &UserOptions::from_config(config).unwrap().clients["alice"];
+        assert_eq!(alice.check_certificate(Some("alice-laptop")), Ok(()));
+        assert!(alice.check_certificate(Some("bob-laptop")).is_err());
+        assert!(alice.check_certificate(None).is_err());
+
+        let bob = Client{key: ClientKey::from_hex(&"cd".repeat(16)).unwrap(), policy: Policy::default(), certificate: None};
+        assert_eq!(bob.check_certificate(Some("alice-laptop")), Ok(()));
+    }
 
-        NormanPacket::new(version, return_output, service, req_type, status, encoding_type, data, multi_packet)
+    #[test]
+    fn tls_flags() {
+        let options = parse(&["--tls-cert", "server.pem", "--tls-key", "server.key", "--tls-client-ca", "ca.pem"]).unwrap();
+        assert_eq!(options.tls, Some(TlsOptions {
+            cert: String::from("server.pem"),
+            key: String::from("server.key"),
+            client_ca: Some(String::from("ca.pem")),
+        }));
+
+        assert!(matches!(parse(&["--tls-cert", "server.pem"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--tls-client-ca", "ca.pem"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--tls-cert", "server.pem", "--tls-key", "server.key", "--callback-port", "7575"]), Err(UsageError::Invalid(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn positional_thread_count_and_callback_port() {
+        let options = parse(&["2", "7575"]).unwrap();
+
+        assert_eq!(options.thread_count, 2);
+        assert_eq!(options.callback_port, Some(7575));
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(parse(&["-h"]), Err(UsageError::HelpRequested));
+        assert_eq!(parse(&["many"]), Err(UsageError::Invalid(String::from("Thread count must be a number, not \"many\""))));
+        assert!(matches!(parse(&["0"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--port", "99999"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--allow-service", "F|TP"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--log-level", "loud"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--config", "/nonexistent/norman.toml"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--threads"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--verbose"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--require-encryption"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--default-shell", "fish"]), Err(UsageError::Invalid(_))));
+        assert!(matches!(parse(&["--key-file", "/nonexistent/norman.key"]), Err(UsageError::Invalid(_))));
+    }
+
+    #[test]
+    fn key_file() {
+        let key = PresharedKey::generate();
+        let path = std::env::temp_dir().join(format!("norman-server-test-{}.key", std::process::id()));
+        fs::write(&path, format!("{}\n", key.to_hex())).unwrap();
+
+        let options = parse(&["--key-file", path.to_str().unwrap(), "--require-encryption"]);
+        fs::remove_file(&path).unwrap();
+        let options = options.unwrap();
+
+        assert_eq!(options.key, Some(key));
+        assert!(options.require_encryption);
+    }
+}
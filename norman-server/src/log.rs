@@ -0,0 +1,52 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+/// How much the server prints. Each level includes everything above it.
+#[derive(Deserialize, PartialEq, PartialOrd, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<LogLevel, String> {
+        match level.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level \"{}\". Expected error, warn, info or debug", level)),
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Info as usize);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` should be printed.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as usize <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Print a message if the server's log level allows it. Errors and warnings go to stderr.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log_enabled($level) {
+            match $level {
+                $crate::LogLevel::Error | $crate::LogLevel::Warn => eprintln!($($arg)*),
+                _ => println!($($arg)*),
+            }
+        }
+    };
+}
@@ -1,46 +1,418 @@
-use std::net::{TcpListener, TcpStream, Shutdown};
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::panic::{self, AssertUnwindSafe};
 use std::io::prelude::*;
-use std::{env, process};
-use cmd_lib::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Instant;
+use std::{env, io, process, thread};
 
 use norman_server::*;
+use norman_protocol::*;
 
 fn main() {
-    let user_args = UserOptions::new(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
-        process::exit(1);
+    let user_args = UserOptions::new(env::args()).unwrap_or_else(|err| match err {
+        UsageError::HelpRequested => {
+            println!("{}", USAGE);
+            process::exit(0);
+        },
+        UsageError::Invalid(message) => {
+            eprintln!("Problem parsing arguments: {}\n\n{}", message, USAGE);
+            process::exit(1);
+        },
     });
+    set_log_level(user_args.log_level);
+
+    let mut listeners = Vec::new();
+    for address in user_args.bind_addresses() {
+        match TcpListener::bind(&address) {
+            Ok(listener) => {
+                log!(LogLevel::Info, "Listening on {}", address);
+                listeners.push(listener);
+            },
+            Err(error) => {
+                log!(LogLevel::Error, "Problem binding {}: {}", address, error);
+                process::exit(1);
+            },
+        }
+    }
+
+    if user_args.clients.is_empty() {
+        log!(LogLevel::Warn, "No clients are configured, so requests will not be authenticated");
+    }
+
+    let tls = user_args.tls.as_ref().map(|tls| tls.server_config().unwrap_or_else(|error| {
+        log!(LogLevel::Error, "{}", error);
+        process::exit(1);
+    }));
+
+    //In-house services are registered here, alongside the built-ins, before the allow-list is applied
+    let mut services = ServiceRegistry::from_options(&user_args);
+    if let Err(error) = services.restrict(user_args.allowed_services.as_deref()) {
+        log!(LogLevel::Error, "{}", error);
+        process::exit(1);
+    }
+    log!(LogLevel::Info, "Offering services {:?}", services);
+
+    let started = Instant::now();
+    let pool = Arc::new(ThreadPool::new(user_args.thread_count));
+    let background = Background::new(user_args.max_background, Arc::clone(&pool));
+    let server = Arc::new(Server{options: user_args, services, started, load: pool.load(), background, replays: Mutex::new(ReplayCache::new()), connections: AtomicUsize::new(0)});
+
+    let acceptors: Vec<thread::JoinHandle<()>> = listeners.into_iter().map(|listener| {
+        let pool = Arc::clone(&pool);
+        let server = Arc::clone(&server);
+        let tls = tls.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        log!(LogLevel::Warn, "Problem accepting connection: {}", error);
+                        continue;
+                    },
+                };
+
+                //Every connection holds two threads, so only so many are taken on
+                let serving = server.connections.fetch_add(1, Ordering::SeqCst);
+                if serving >= server.options.max_connections {
+                    server.connections.fetch_sub(1, Ordering::SeqCst);
+                    log!(LogLevel::Warn, "Turning away a connection: already serving {}", serving);
+                    continue;
+                }
+
+                let pool = Arc::clone(&pool);
+                let server = Arc::clone(&server);
+                let tls = tls.clone();
+
+                //Each connection gets a thread to read requests and one to write replies. The requests themselves run on the pool
+                thread::spawn(move || {
+                    serve(stream, &server, &pool, tls.as_ref());
+                    server.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        })
+    }).collect();
+
+    for acceptor in acceptors {
+        let _ = acceptor.join();
+    }
+
+    /// What answering requests needs, shared by every connection.
+    struct Server {
+        options: UserOptions,
+        services: ServiceRegistry,
+        started: Instant,
+        load: PoolLoad,
+        /// Work fire-and-forget requests have left running.
+        background: Background,
+        /// Signatures already accepted, so a captured request can't be run again.
+        replays: Mutex<ReplayCache>,
+        /// The number of connections being served.
+        connections: AtomicUsize,
+    }
+
+    /// The requests read off one connection that haven't been answered yet.
+    #[derive(Default)]
+    struct InFlight {
+        count: Mutex<usize>,
+        answered: Condvar,
+    }
+
+    impl InFlight {
+        fn start(&self) {
+            *self.count.lock().unwrap() += 1;
+        }
+
+        fn finish(&self) {
+            *self.count.lock().unwrap() -= 1;
+            self.answered.notify_all();
+        }
+
+        fn is_empty(&self) -> bool {
+            *self.count.lock().unwrap() == 0
+        }
+
+        /// Wait until fewer than `limit` requests are unanswered.
+        fn wait_below(&self, limit: usize) {
+            let _count = self.answered.wait_while(self.count.lock().unwrap(), |count| *count >= limit).unwrap();
+        }
+    }
 
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(user_args.thread_count);
+    fn serve(stream: TcpStream, server: &Arc<Server>, pool: &ThreadPool, tls: Option<&Arc<rustls::ServerConfig>>) {
+        let peer = match stream.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => String::from("unknown peer"),
+        };
+        let connection = match Connection::accept(stream, tls, server.options.idle_timeout) {
+            Ok(connection) => connection,
+            Err(error) => {
+                log!(LogLevel::Warn, "TLS handshake with {} failed: {}", peer, error);
+                return;
+            },
+        };
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+        //Name the client by its certificate where it has one
+        let certificate = connection.peer_name();
+        let peer = match &certificate {
+            Some(name) => format!("{} ({})", name, peer),
+            None => peer,
+        };
+        log!(LogLevel::Debug, "Accepted connection from {}", peer);
 
-        pool.execute(|| {
-            handle_request(stream);
-        });
+        handle_connection(connection, &peer, certificate, server, pool);
     }
 
-    fn handle_request(mut stream: TcpStream) {
-        let mut buffer = [0; 512];
-        stream.read(&mut buffer).unwrap();
+    /// Read requests off the connection and run each on the pool, writing the replies back as they finish.
+    ///
+    /// A client can send any number of requests without waiting for replies.
+    /// Replies go out in the order their requests finish, tagged with the
+    /// request's uid. Once a connection has `max_in_flight` requests
+    /// unanswered, nothing more is read from it until a reply goes out. A
+    /// connection that sends nothing for the idle timeout while none of its
+    /// requests are running is closed.
+    fn handle_connection(connection: Connection, peer: &str, certificate: Option<String>, server: &Arc<Server>, pool: &ThreadPool) {
+        let options = &server.options;
+        let (reader, mut writer) = match connection.split() {
+            Ok(halves) => halves,
+            Err(error) => {
+                log!(LogLevel::Warn, "Problem setting up connection from {}: {}", peer, error);
+                return;
+            },
+        };
+        let timeouts = reader.tcp_stream().set_read_timeout(Some(options.idle_timeout)).and_then(|_| writer.tcp_stream().set_write_timeout(Some(options.idle_timeout)));
+        if let Err(error) = timeouts {
+            log!(LogLevel::Warn, "Problem setting timeouts: {}", error);
+            return;
+        }
 
-        let mut ret_stream = TcpStream::connect("127.0.0.1:7575").unwrap();
+        let mut reader = PacketReader::with_max_packet_size(reader, options.max_packet_size);
+        let (replies, finished) = mpsc::channel::<(Vec<NormanPacket>, WireFormat)>();
+        let in_flight = Arc::new(InFlight::default());
 
-        let packet = String::from_utf8(buffer.to_vec()).unwrap();
+        //Replies are written as soon as they are ready, whatever the reader is waiting on
+        let writer_thread = {
+            let (server, in_flight) = (Arc::clone(server), Arc::clone(&in_flight));
+            thread::spawn(move || {
+                let mut broken = false;
+                for (fragments, format) in finished {
+                    //Once the connection breaks, replies are still counted off so the reader is never left waiting
+                    if !broken {
+                        if let Err(error) = deliver(&mut writer, &server.options, &fragments, format) {
+                            log!(LogLevel::Warn, "Problem sending response: {}", error);
+                            //Stop the reader too, as nothing more can be answered
+                            let _ = writer.tcp_stream().shutdown(Shutdown::Both);
+                            broken = true;
+                        }
+                    }
+                    in_flight.finish();
+                }
+            })
+        };
 
-        println!("Got norman packet: {}", packet);
+        //Keep answering requests until the client hangs up
+        loop {
+            //A client can only queue so much work before it has to wait for answers
+            in_flight.wait_below(options.max_in_flight);
 
-        let packet = NormanPacket::from_string(packet);
+            let packet = reader.read_packet();
+            let format = reader.format().unwrap_or(WireFormat::Framed);
 
-        let comm_out = run_fun!("{}", &packet.data.data);
+            match packet {
+                Ok(Some(packet)) => {
+                    log!(LogLevel::Debug, "Got norman packet: {:?}", packet);
+
+                    let (server, peer, certificate, replies) = (Arc::clone(server), peer.to_string(), certificate.clone(), replies.clone());
+                    in_flight.start();
+                    pool.execute(move || {
+                        //Whatever goes wrong, the client hears back about this uid
+                        let (uid, service) = (packet.meta.uid, packet.header.service.clone());
+                        let fragments = panic::catch_unwind(AssertUnwindSafe(|| answer(packet, &peer, certificate.as_deref(), &server, format))).unwrap_or_else(|_| {
+                            log!(LogLevel::Error, "Answering a request from {} panicked", peer);
+                            vec![NormanPacket::new(String::from(PROTOCOL_VERSION), true, service, RequestType::ERROR, Status::INTERNAL_ERROR, String::from("None"), String::from("The request failed unexpectedly"), false).with_uid(uid)]
+                        });
+                        let _ = replies.send((fragments, format));
+                    });
+                },
+                //A client waiting on a long request may well say nothing in the meantime
+                Err(ReadError::Io(ref error)) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if in_flight.is_empty() {
+                        log!(LogLevel::Debug, "Closing idle connection from {}", peer);
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                //TLS peers that hang up without a close_notify
+                Err(ReadError::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(ReadError::Io(error)) => {
+                    log!(LogLevel::Warn, "Problem reading from connection: {}", error);
+                    break;
+                },
+                Err(error) => {
+                    log!(LogLevel::Warn, "Rejecting malformed packet from {}: {}", peer, error);
+
+                    let status = match error {
+                        ReadError::TooLarge{..} => Status::TOO_LARGE,
+                        _ => Status::BAD_PACKET,
+                    };
+                    let reply = NormanPacket::new(String::from(PROTOCOL_VERSION), false, Service::UNKNOWN, RequestType::ERROR, status, String::from("None"), error.to_string(), false);
+                    in_flight.start();
+                    let _ = replies.send((into_fragments(vec![reply], format, options.fragment_size), format));
+
+                    //Only a packet that was read in full can be skipped over
+                    if !matches!(error, ReadError::Parse(_)) {
+                        break;
+                    }
+                },
+            }
+        }
+
+        //The client has stopped sending but still gets replies to what it sent
+        drop(replies);
+        let _ = writer_thread.join();
+    }
+
+    /// Everything sent back for one request, ready for the wire.
+    fn answer(packet: NormanPacket, peer: &str, certificate: Option<&str>, server: &Server, format: WireFormat) -> Vec<NormanPacket> {
+        let uid = packet.meta.uid;
+        let (mut responses, seal_key) = respond(packet, peer, certificate, server);
+        for response in &mut responses {
+            response.meta.uid = uid;
+        }
+
+        let mut fragments = into_fragments(responses, format, server.options.fragment_size);
+        if let Some(key) = seal_key {
+            for fragment in &mut fragments {
+                fragment.encrypt(key);
+            }
+        }
+        fragments
+    }
+
+    /// Authenticate and decrypt a request, then run it. Also gives the key to encrypt the response with.
+    ///
+    /// `certificate` names the TLS certificate the connection presented, which
+    /// must be the signing client's own when its settings name one.
+    fn respond<'a>(mut packet: NormanPacket, peer: &str, certificate: Option<&str>, server: &'a Server) -> (Vec<NormanPacket>, Option<&'a PresharedKey>) {
+        let (options, services) = (&server.options, &server.services);
+        let (peer, client) = match authenticate(&packet, server) {
+            Ok(Some((client_id, client))) => (format!("{} at {}", client_id, peer), Some(client)),
+            Ok(None) => (peer.to_string(), None),
+            Err(error) => {
+                log!(LogLevel::Warn, "Rejecting unauthenticated request from {}: {}", peer, error);
+                return (vec![error_response(&packet, Status::UNAUTHORIZED, String::from("Request could not be authenticated"))], None);
+            },
+        };
+
+        //A signing key alone doesn't prove which machine the request came from
+        if let Some(Err(reason)) = client.map(|client| client.check_certificate(certificate)) {
+            log!(LogLevel::Warn, "Rejecting request from {}: {}", peer, reason);
+            return (vec![error_response(&packet, Status::UNAUTHORIZED, String::from("Request could not be authenticated"))], None);
+        }
+
+        let encrypted = packet.encryption.encoding_type != ENCRYPTION_NONE;
+        match packet.decrypt(options.key.as_ref()) {
+            Err(error @ CryptoError::UnsupportedEncryption(_)) => {
+                log!(LogLevel::Warn, "Rejecting request from {}: {}", peer, error);
+                return (vec![error_response(&packet, Status::UNSUPPORTED, error.to_string())], None);
+            },
+            Err(error) => {
+                log!(LogLevel::Warn, "Rejecting request from {}: {}", peer, error);
+                return (vec![error_response(&packet, Status::UNAUTHORIZED, error.to_string())], None);
+            },
+            Ok(()) if options.require_encryption && !encrypted => {
+                log!(LogLevel::Warn, "Rejecting unencrypted request from {}", peer);
+                return (vec![error_response(&packet, Status::UNAUTHORIZED, String::from("This server only accepts encrypted requests"))], None);
+            },
+            Ok(()) => {},
+        }
+
+        //Every answer from here on goes back in the cipher the request was sent in
+        let seal_key = match encrypted {
+            true => options.key.as_ref(),
+            false => None,
+        };
+
+        //Neither pings nor capability queries run anything
+        match packet.meta.req_type {
+            RequestType::TEST => {
+                log!(LogLevel::Debug, "{} pinged the server", peer);
+                return (vec![health_response(&packet, &health(server.started.elapsed(), &server.load))], seal_key);
+            },
+            RequestType::CAPABILITIES => {
+                log!(LogLevel::Info, "{} asked for the server's capabilities", peer);
+                return (vec![capabilities_response(&packet, &options.capabilities(services))], seal_key);
+            },
+            _ => {},
+        }
+
+        let service = packet.header.service.name();
+        let handler = match services.get(service) {
+            Some(handler) => handler,
+            None => {
+                log!(LogLevel::Warn, "Rejecting request from {} for unavailable service {}", peer, service);
+                return (vec![error_response(&packet, Status::UNSUPPORTED, format!("The {} service is not available on this server", service))], seal_key);
+            },
+        };
+
+        let invocation = match Invocation::from_packet(&packet, options.default_shell.as_deref()) {
+            Ok(invocation) => invocation,
+            Err(reason) => {
+                log!(LogLevel::Warn, "Rejecting request from {}: {}", peer, reason);
+                return (vec![error_response(&packet, Status::BAD_REQUEST, reason)], seal_key);
+            },
+        };
+
+        let run_as = match client {
+            Some(client) => {
+                if let Err(reason) = client.policy.check(&packet.header.service, &invocation) {
+                    log!(LogLevel::Warn, "Denied {} running \"{}\": {}", peer, invocation, reason);
+                    return (vec![error_response(&packet, Status::FORBIDDEN, reason)], seal_key);
+                }
+                client.policy.run_as.clone()
+            },
+            None => RunAs::default(),
+        };
+
+        let request = Request{packet: &packet, invocation: &invocation, peer: &peer, run_as: &run_as, background: &server.background};
+        (dispatch(handler, &request), seal_key)
+    }
+
+    /// The client that signed the request, or `None` when the server has no clients configured.
+    fn authenticate<'a>(packet: &NormanPacket, server: &'a Server) -> Result<Option<(String, &'a Client)>, AuthError> {
+        let options = &server.options;
+        if options.clients.is_empty() {
+            return Ok(None);
+        }
+
+        let client_id = packet.client_id().ok_or(AuthError::Unsigned)?;
+        let client = options.clients.get(client_id).ok_or_else(|| AuthError::UnknownClient(client_id.to_string()))?;
+        packet.verify(&client.key)?;
+        server.replays.lock().unwrap().check(packet)?;
+
+        Ok(Some((client_id.to_string(), client)))
+    }
+
+    fn deliver(writer: &mut WriteHalf, options: &UserOptions, fragments: &[NormanPacket], format: WireFormat) -> io::Result<()> {
+        match options.callback_port {
+            Some(port) => send_callback(writer.tcp_stream(), port, fragments, format),
+            None => send_response(writer, fragments, format),
+        }
+    }
+
+    fn send_response<W: Write>(mut stream: W, fragments: &[NormanPacket], format: WireFormat) -> io::Result<()> {
+        for fragment in fragments {
+            stream.write_all(&fragment.encode(format))?;
+        }
+        stream.flush()
+    }
 
-        let response = NormanPacket::new(packet.header.version, packet.header.return_output, packet.header.service, RequestType::RETURN, Status::FINE{code: 200}, String::from("None"), comm_out.unwrap(), false);        
+    //Legacy delivery for clients that listen for the response on a port of their own
+    fn send_callback(stream: &TcpStream, port: u16, fragments: &[NormanPacket], format: WireFormat) -> io::Result<()> {
+        let client = SocketAddr::new(stream.peer_addr()?.ip(), port);
+        let ret_stream = TcpStream::connect(client)?;
 
-        ret_stream.write(response.as_string().as_bytes()).unwrap();
-        stream.flush().unwrap();
-        ret_stream.flush().unwrap();
-        ret_stream.shutdown(Shutdown::Both).unwrap();
+        send_response(&ret_stream, fragments, format)?;
+        ret_stream.shutdown(Shutdown::Both)
     }
 }
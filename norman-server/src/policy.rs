@@ -0,0 +1,219 @@
+use regex::Regex;
+
+use norman_protocol::Service;
+
+use crate::{ClientSettings, Invocation, RunAs};
+
+/// Characters that let a shell command run more than one program.
+const SHELL_OPERATORS: &[char] = &[';', '|', '&', '`', '$', '(', ')', '<', '>', '\n', '\r'];
+
+/// Characters that make a shell see a different command from the one the patterns see.
+const SHELL_QUOTING: &[char] = &['\'', '"', '\\', '*', '?', '[', ']', '{', '}', '~'];
+
+/// What a client may run, and where and as whom it runs.
+///
+/// A command must pass every restriction that is set: its service must be
+/// listed, its program must be one of the allowed executables, the whole
+/// command must match one of the allow patterns and it must match none of the
+/// deny patterns. Patterns see the command as it would be typed, with direct
+/// arguments quoted. A client with nothing set may run anything.
+///
+/// A shell would undo quoting and expand globs after the patterns have looked,
+/// so once executables, allow or deny are set, commands can't be run through a
+/// shell unless `allow_shell` is on. Even then they may not use shell
+/// operators, quotes, escapes or globs.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    services: Option<Vec<Service>>,
+    executables: Option<Vec<String>>,
+    allow: Option<Vec<Regex>>,
+    deny: Vec<Regex>,
+    allow_shell: bool,
+    pub run_as: RunAs,
+}
+
+impl Policy {
+    pub fn from_settings(settings: &ClientSettings) -> Result<Policy, String> {
+        let services = match &settings.services {
+            Some(names) => Some(names.iter().map(|name| Service::new(name).map_err(|error| error.to_string())).collect::<Result<Vec<Service>, String>>()?),
+            None => None,
+        };
+
+        //Allow patterns have to match the whole command, not just part of it
+        let allow = match &settings.allow {
+            Some(patterns) => Some(patterns.iter().map(|pattern| compile(&format!("^(?:{})$", pattern), pattern)).collect::<Result<Vec<Regex>, String>>()?),
+            None => None,
+        };
+        let deny = settings.deny.iter().flatten().map(|pattern| compile(pattern, pattern)).collect::<Result<Vec<Regex>, String>>()?;
+
+        let run_as = RunAs {
+            working_dir: settings.working_dir.clone(),
+            user: settings.user.clone(),
+        };
+
+        let allow_shell = settings.allow_shell.unwrap_or(false);
+
+        Ok(Policy{services, executables: settings.executables.clone(), allow, deny, allow_shell, run_as})
+    }
+
+    /// Whether the client may make `invocation` on `service`, and why not if it can't.
+    pub fn check(&self, service: &Service, invocation: &Invocation) -> Result<(), String> {
+        if let Some(services) = &self.services {
+            if !services.contains(service) {
+                return Err(format!("Not permitted to use the {} service", service));
+            }
+        }
+
+        //Only a single plain command can be checked the way a shell would run it
+        if let Invocation::Shell{command, ..} = invocation {
+            if self.restricts_commands() {
+                if !self.allow_shell {
+                    return Err(String::from("Not permitted to run commands through a shell"));
+                }
+                if command.contains(SHELL_OPERATORS) {
+                    return Err(String::from("Not permitted to use shell operators"));
+                }
+                if command.contains(SHELL_QUOTING) {
+                    return Err(String::from("Not permitted to use quotes, escapes or globs in shell commands"));
+                }
+            }
+        }
+
+        if let Some(executables) = &self.executables {
+            let program = match invocation {
+                Invocation::Direct(argv) => argv[0].as_str(),
+                Invocation::Shell{command, ..} => command.split_whitespace().next().unwrap_or(""),
+            };
+            if !executables.iter().any(|executable| executable == program) {
+                return Err(format!("Not permitted to run \"{}\"", program));
+            }
+        }
+
+        let command = invocation.to_string();
+
+        if let Some(allow) = &self.allow {
+            if !allow.iter().any(|pattern| pattern.is_match(&command)) {
+                return Err(String::from("Command is not on the allow list"));
+            }
+        }
+
+        if let Some(pattern) = self.deny.iter().find(|pattern| pattern.is_match(&command)) {
+            return Err(format!("Command matches the deny pattern \"{}\"", pattern));
+        }
+
+        Ok(())
+    }
+
+    /// Whether anything limits which commands the client may run.
+    fn restricts_commands(&self) -> bool {
+        self.executables.is_some() || self.allow.is_some() || !self.deny.is_empty()
+    }
+}
+
+//Regexes can't be compared, so policies compare by their patterns
+impl PartialEq for Policy {
+    fn eq(&self, other: &Policy) -> bool {
+        let patterns = |regexes: &[Regex]| regexes.iter().map(|regex| regex.as_str().to_string()).collect::<Vec<String>>();
+
+        self.services == other.services
+            && self.executables == other.executables
+            && self.allow.as_deref().map(patterns) == other.allow.as_deref().map(patterns)
+            && patterns(&self.deny) == patterns(&other.deny)
+            && self.allow_shell == other.allow_shell
+            && self.run_as == other.run_as
+    }
+}
+
+fn compile(pattern: &str, original: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|error| format!("Bad pattern \"{}\": {}", original, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        let settings: ClientSettings = toml::from_str(&format!("key = \"{}\"\n{}", "ab".repeat(16), toml)).unwrap();
+        Policy::from_settings(&settings).unwrap()
+    }
+
+    fn direct(command: &str) -> Invocation {
+        Invocation::Direct(shell_words::split(command).unwrap())
+    }
+
+    fn sh(command: &str) -> Invocation {
+        Invocation::Shell{shell: String::from("sh"), command: command.to_string()}
+    }
+
+    #[test]
+    fn unrestricted_by_default() {
+        let policy = policy("");
+
+        assert_eq!(policy.check(&Service::SHELL, &sh("rm -rf /tmp/scratch; reboot")), Ok(()));
+        assert_eq!(policy.check(&Service::AWS, &direct("stop i-0123")), Ok(()));
+    }
+
+    #[test]
+    fn read_only_engineer() {
+        let policy = policy(r#"
+            services = ["shell"]
+            executables = ["ls", "cat", "df", "uptime", "/usr/bin/journalctl"]
+            deny = ['/etc/shadow']
+            allow_shell = true
+            working_dir = "/var/log"
+            user = "nobody"
+        "#);
+
+        assert_eq!(policy.check(&Service::SHELL, &direct("ls -la /var/log")), Ok(()));
+        assert_eq!(policy.check(&Service::SHELL, &sh("ls -la /var/log")), Ok(()));
+        assert_eq!(policy.check(&Service::SHELL, &direct("/usr/bin/journalctl -u norman")), Ok(()));
+        assert_eq!(policy.check(&Service::SHELL, &direct("cat '$(reboot)'")), Ok(()));
+        assert!(policy.check(&Service::DOCKER, &direct("ps")).is_err());
+        assert!(policy.check(&Service::SHELL, &direct("rm -rf /")).is_err());
+        assert!(policy.check(&Service::SHELL, &direct("/bin/ls")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("ls; rm -rf /")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat $(echo /etc/passwd)")).is_err());
+        assert!(policy.check(&Service::SHELL, &direct("cat /etc/shadow")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat /etc/shadow")).is_err());
+        assert_eq!(policy.run_as, RunAs{working_dir: Some(String::from("/var/log")), user: Some(String::from("nobody"))});
+    }
+
+    #[test]
+    fn allow_patterns_match_whole_command() {
+        let policy = policy(r#"allow = ['systemctl status \S+', 'docker ps']"#);
+
+        assert_eq!(policy.check(&Service::SHELL, &direct("systemctl status nginx")), Ok(()));
+        assert!(policy.check(&Service::SHELL, &sh("systemctl status nginx; systemctl stop nginx")).is_err());
+        assert!(policy.check(&Service::SHELL, &direct("systemctl status 'nginx; reboot'")).is_err());
+        assert!(policy.check(&Service::SHELL, &direct("sudo docker ps")).is_err());
+    }
+
+    #[test]
+    fn shells_cant_get_past_patterns() {
+        let no_shell = policy(r#"deny = ['/etc/shadow']"#);
+
+        assert_eq!(no_shell.check(&Service::SHELL, &direct("cat /etc/passwd")), Ok(()));
+        assert!(no_shell.check(&Service::SHELL, &sh("cat /etc/passwd")).is_err());
+        assert!(no_shell.check(&Service::SHELL, &sh("cat /etc/sha'd'ow")).is_err());
+
+        let policy = policy(r#"
+            deny = ['/etc/shadow']
+            allow_shell = true
+        "#);
+
+        assert_eq!(policy.check(&Service::SHELL, &sh("cat /etc/passwd")), Ok(()));
+        assert!(policy.check(&Service::SHELL, &sh("cat /etc/sha'd'ow")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat /etc/sha\\dow")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat /etc/shad*")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat /etc/shado?")).is_err());
+        assert!(policy.check(&Service::SHELL, &sh("cat $(printf /etc/shadow)")).is_err());
+    }
+
+    #[test]
+    fn bad_settings() {
+        let settings = |toml: &str| toml::from_str::<ClientSettings>(&format!("key = \"{}\"\n{}", "ab".repeat(16), toml)).unwrap();
+
+        assert!(Policy::from_settings(&settings(r#"services = ["F T P"]"#)).is_err());
+        assert!(Policy::from_settings(&settings(r#"deny = ['(unclosed']"#)).is_err());
+    }
+}
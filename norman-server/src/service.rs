@@ -0,0 +1,427 @@
+//! The services requests are handed to once they have been let in.
+//!
+//! Each service is a `ServiceHandler` registered under the name clients send
+//! in the packet header. The built-in SHELL, DOCKER and AWS services are
+//! registered by `ServiceRegistry::from_options`; others can be added with
+//! `register` before the server starts listening. Once everything is
+//! registered, `restrict` holds the registry to the configured allow-list.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use norman_protocol::{Capabilities, Health, NormanPacket, RequestType, Service, Status, WireFormat};
+
+use crate::aws::AwsService;
+use crate::docker::Docker;
+use crate::{run_captured, spawn_detached, Background, CommandOutput, Invocation, LogLevel, RunAs, UserOptions};
+
+/// A request that has been authenticated, decrypted and allowed by the client's policy.
+pub struct Request<'a> {
+    pub packet: &'a NormanPacket,
+    pub invocation: &'a Invocation,
+    /// Who sent the request, for the log.
+    pub peer: &'a str,
+    pub run_as: &'a RunAs,
+    /// Where fire-and-forget requests leave their work running.
+    pub background: &'a Background,
+}
+
+/// Something that carries out requests for a service.
+pub trait ServiceHandler: Send + Sync {
+    /// Carry out a request, giving back the packets to answer it with.
+    ///
+    /// Several packets are sent to the client as one multi-packet message.
+    fn handle(&self, request: &Request) -> Vec<NormanPacket>;
+}
+
+/// The handler for each service the server offers, by service name.
+#[derive(Default)]
+pub struct ServiceRegistry {
+    handlers: BTreeMap<String, Box<dyn ServiceHandler>>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> ServiceRegistry {
+        ServiceRegistry::default()
+    }
+
+    /// Every built-in service, set up from the options.
+    ///
+    /// The allow-list isn't applied here, as in-house services may still be
+    /// registered; call `restrict` once they are.
+    pub fn from_options(options: &UserOptions) -> ServiceRegistry {
+        let mut registry = ServiceRegistry::new();
+        registry.register(Service::SHELL.name(), ShellService);
+        registry.register(Service::DOCKER.name(), Docker::new(&options.docker_socket));
+        registry.register(Service::AWS.name(), AwsService::new(options.aws_endpoint.clone(), &options.aws_region));
+        registry
+    }
+
+    /// Hand requests for the service called `name` to `handler`, replacing any handler it had.
+    pub fn register<H: ServiceHandler + 'static>(&mut self, name: &str, handler: H) {
+        self.handlers.insert(name.to_uppercase(), Box::new(handler));
+    }
+
+    /// Drop every service not in `allowed`, failing if it names one that isn't registered.
+    ///
+    /// Leaves the registry alone when `allowed` is unset.
+    pub fn restrict(&mut self, allowed: Option<&[Service]>) -> Result<(), String> {
+        let allowed = match allowed {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+
+        if let Some(unknown) = allowed.iter().find(|service| self.get(service.name()).is_none()) {
+            let known = self.names().collect::<Vec<&str>>().join(", ");
+            return Err(format!("Unknown service \"{}\". Expected one of {}", unknown, known));
+        }
+
+        self.handlers.retain(|name, _| allowed.iter().any(|service| service.name().eq_ignore_ascii_case(name)));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ServiceHandler> {
+        self.handlers.get(&name.to_uppercase()).map(|handler| handler.as_ref())
+    }
+
+    /// The names of the registered services, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl fmt::Debug for ServiceRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Hand a request to its handler, answering INTERNAL_ERROR if the handler panics or gives nothing back.
+pub fn dispatch(handler: &dyn ServiceHandler, request: &Request) -> Vec<NormanPacket> {
+    let (packet, peer) = (request.packet, request.peer);
+    let service = &packet.header.service;
+
+    match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(request))) {
+        Ok(responses) if !responses.is_empty() => responses,
+        Ok(_) => {
+            log!(LogLevel::Warn, "The {} service gave no response to {}", service, peer);
+            vec![error_response(packet, Status::INTERNAL_ERROR, format!("The {} service gave no response", service))]
+        },
+        Err(_) => {
+            log!(LogLevel::Error, "The {} service panicked handling a request from {}", service, peer);
+            vec![error_response(packet, Status::INTERNAL_ERROR, format!("The {} service failed unexpectedly", service))]
+        },
+    }
+}
+
+/// Runs requests as processes on the server.
+pub struct ShellService;
+
+impl ServiceHandler for ShellService {
+    fn handle(&self, request: &Request) -> Vec<NormanPacket> {
+        let (packet, invocation, peer) = (request.packet, request.invocation, request.peer);
+
+        let response = match packet.header.return_output {
+            true => match run_captured(invocation, request.run_as) {
+                Ok(output) => {
+                    log!(LogLevel::Info, "{} ran \"{}\", exit code {}", peer, invocation, output.exit_code);
+                    output_response(packet, output)
+                },
+                Err(error) => {
+                    log!(LogLevel::Warn, "Problem running \"{}\": {}", invocation, error);
+                    error_response(packet, Status::INTERNAL_ERROR, format!("Problem running command: {}", error))
+                },
+            },
+            //Fire and forget: acknowledge the request and leave the command running
+            false => match request.background.reserve() {
+                Ok(slot) => match spawn_detached(invocation, request.run_as, slot) {
+                    Ok(id) => {
+                        log!(LogLevel::Info, "{} started \"{}\" in the background as process {}", peer, invocation, id);
+                        accepted_response(packet, format!("Started as process {}", id))
+                    },
+                    Err(error) => {
+                        log!(LogLevel::Warn, "Problem starting \"{}\": {}", invocation, error);
+                        error_response(packet, Status::INTERNAL_ERROR, format!("Problem starting command: {}", error))
+                    },
+                },
+                Err(reason) => {
+                    log!(LogLevel::Warn, "Turning away \"{}\" from {}: {}", invocation, peer, reason);
+                    error_response(packet, Status::TOO_LARGE, reason)
+                },
+            },
+        };
+        vec![response]
+    }
+}
+
+/// Carry out a request with a backend that takes a verb and its arguments rather than starting a process.
+///
+/// `status` picks the status to answer a failure with. Requests the backend
+/// couldn't make sense of get `usage` added to the message.
+pub(crate) fn run_backend<F, E>(request: &Request, run: F, status: fn(&E) -> Status, usage: &str) -> NormanPacket
+    where
+        F: FnOnce(&[String]) -> Result<CommandOutput, E> + Send + 'static,
+        E: fmt::Display,
+{
+    let (packet, invocation, peer) = (request.packet, request.invocation, request.peer);
+    let service = packet.header.service.name().to_string();
+    let argv = match invocation {
+        Invocation::Direct(argv) => argv.clone(),
+        Invocation::Shell{..} => return error_response(packet, Status::BAD_REQUEST, format!("{} requests can't be run through a shell", service)),
+    };
+
+    if !packet.header.return_output {
+        //Fire and forget: acknowledge the request and let the backend carry on from the pool
+        let slot = match request.background.reserve() {
+            Ok(slot) => slot,
+            Err(reason) => {
+                log!(LogLevel::Warn, "Turning away {} \"{}\" from {}: {}", service, invocation, peer, reason);
+                return error_response(packet, Status::TOO_LARGE, reason);
+            },
+        };
+        let peer = peer.to_string();
+        let command = invocation.to_string();
+        request.background.execute(slot, move || match run(&argv) {
+            Ok(_) => log!(LogLevel::Info, "{} ran {} \"{}\" in the background", peer, service, command),
+            Err(error) => log!(LogLevel::Warn, "Problem running {} \"{}\": {}", service, command, error),
+        });
+
+        return accepted_response(packet, String::from("Started in the background"));
+    }
+
+    match run(&argv) {
+        Ok(output) => {
+            log!(LogLevel::Info, "{} ran {} \"{}\", exit code {}", peer, service, invocation, output.exit_code);
+            output_response(packet, output)
+        },
+        Err(error) => {
+            log!(LogLevel::Warn, "Problem running {} \"{}\": {}", service, invocation, error);
+
+            let status = status(&error);
+            let message = match status {
+                Status::BAD_REQUEST => format!("{}\n\n{}", error, usage),
+                _ => error.to_string(),
+            };
+            error_response(packet, status, message)
+        },
+    }
+}
+
+/// A RETURN packet carrying what a finished command left behind.
+pub fn output_response(packet: &NormanPacket, output: CommandOutput) -> NormanPacket {
+    let status = match output.exit_code {
+        0 => Status::OK,
+        _ => Status::INTERNAL_ERROR,
+    };
+    let mut response = NormanPacket::new(packet.header.version.clone(), true, packet.header.service.clone(), RequestType::RETURN, status, String::from("None"), output.stdout, false);
+    response.data.stderr = output.stderr;
+    response.data.exit_code = Some(output.exit_code);
+    response
+}
+
+/// A RETURN packet acknowledging a request left running in the background.
+pub fn accepted_response(packet: &NormanPacket, message: String) -> NormanPacket {
+    NormanPacket::new(packet.header.version.clone(), false, packet.header.service.clone(), RequestType::RETURN, Status::ACCEPTED, String::from("None"), message, false)
+}
+
+/// A RETURN packet answering a CAPABILITIES request.
+pub fn capabilities_response(packet: &NormanPacket, capabilities: &Capabilities) -> NormanPacket {
+    NormanPacket::new(packet.header.version.clone(), true, packet.header.service.clone(), RequestType::RETURN, Status::OK, String::from("None"), capabilities.to_string(), false)
+}
+
+/// A TEST packet answering a ping.
+pub fn health_response(packet: &NormanPacket, health: &Health) -> NormanPacket {
+    NormanPacket::new(packet.header.version.clone(), true, packet.header.service.clone(), RequestType::TEST, Status::PING, String::from("None"), health.to_string(), false)
+}
+
+pub fn error_response(packet: &NormanPacket, status: Status, message: String) -> NormanPacket {
+    NormanPacket::new(packet.header.version.clone(), packet.header.return_output, packet.header.service.clone(), RequestType::ERROR, status, String::from("None"), message, false)
+}
+
+/// Lay the response packets out as one message for the wire.
+///
+/// In the framed format each packet is split to fit the fragment size and
+/// the fragments are numbered in order. The text format has no room for
+/// fragment numbers or separate output fields, so text peers get everything
+/// in one packet's data: stdout, then stderr, then the exit code on a line of
+/// its own when the command failed. Output the text format can't carry, such
+/// as a `|`, is answered with UNSUPPORTED instead.
+pub fn into_fragments(responses: Vec<NormanPacket>, format: WireFormat, fragment_size: usize) -> Vec<NormanPacket> {
+    let mut fragments: Vec<NormanPacket> = match format {
+        WireFormat::Framed => responses.iter().flat_map(|response| response.split(fragment_size)).collect(),
+        WireFormat::Text => responses.into_iter().map(fold_for_text).reduce(|earlier, mut later| {
+            later.data.data = earlier.data.data + &later.data.data;
+            later
+        }).map(|packet| match packet.to_text() {
+            Ok(_) => packet,
+            Err(error) => error_response(&packet, Status::UNSUPPORTED, error.to_string()).with_uid(packet.meta.uid),
+        }).into_iter().collect(),
+    };
+    let last = fragments.len().saturating_sub(1);
+    for (sequence, fragment) in fragments.iter_mut().enumerate() {
+        fragment.terminator.sequence = sequence as u32;
+        fragment.terminator.multi_packet = sequence != last;
+    }
+    fragments
+}
+
+/// Move what only the framed format can carry into the data field.
+fn fold_for_text(mut packet: NormanPacket) -> NormanPacket {
+    let stderr = std::mem::take(&mut packet.data.stderr);
+    packet.data.data.push_str(&stderr);
+
+    if let Some(code) = packet.data.exit_code.take().filter(|code| *code != 0) {
+        if !packet.data.data.is_empty() && !packet.data.data.ends_with('\n') {
+            packet.data.data.push('\n');
+        }
+        packet.data.data.push_str(&format!("exit code {}\n", code));
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use norman_protocol::PROTOCOL_VERSION;
+
+    fn request(service: Service, command: &str) -> NormanPacket {
+        NormanPacket::new(PROTOCOL_VERSION.to_string(), true, service, RequestType::REQUEST, Status::OK, String::from("None"), command.to_string(), false)
+    }
+
+    fn background() -> Background {
+        Background::new(4, std::sync::Arc::new(crate::ThreadPool::new(1)))
+    }
+
+    /// An in-house service that answers with its command, one word per packet.
+    struct Echo;
+
+    impl ServiceHandler for Echo {
+        fn handle(&self, request: &Request) -> Vec<NormanPacket> {
+            request.invocation.to_string().split(' ').map(|word| {
+                NormanPacket::new(PROTOCOL_VERSION.to_string(), true, Service::new("ECHO").unwrap(), RequestType::RETURN, Status::OK, String::from("None"), word.to_string(), false)
+            }).collect()
+        }
+    }
+
+    #[test]
+    fn registers_allowed_builtins() {
+        let options = UserOptions::from_config(crate::ConfigFile{allowed_services: Some(vec![String::from("shell"), String::from("aws")]), ..crate::ConfigFile::default()}).unwrap();
+        let mut registry = ServiceRegistry::from_options(&options);
+        registry.restrict(options.allowed_services.as_deref()).unwrap();
+
+        assert_eq!(registry.names().collect::<Vec<&str>>(), vec!["AWS", "SHELL"]);
+        assert!(registry.get("DOCKER").is_none());
+
+        registry.register("echo", Echo);
+        let packet = request(Service::SHELL, "hello there");
+        let invocation = Invocation::Direct(vec![String::from("hello"), String::from("there")]);
+        let responses = registry.get("Echo").unwrap().handle(&Request{packet: &packet, invocation: &invocation, peer: "test", run_as: &RunAs::default(), background: &background()});
+
+        assert_eq!(responses.iter().map(|response| response.data.data.as_str()).collect::<Vec<&str>>(), vec!["hello", "there"]);
+    }
+
+    #[test]
+    fn allows_in_house_services() {
+        let options = UserOptions::from_config(crate::ConfigFile{allowed_services: Some(vec![String::from("shell"), String::from("echo")]), ..crate::ConfigFile::default()}).unwrap();
+        let mut registry = ServiceRegistry::from_options(&options);
+        registry.register("echo", Echo);
+        registry.register("backup", Echo);
+
+        assert_eq!(registry.restrict(options.allowed_services.as_deref()), Ok(()));
+        assert_eq!(registry.names().collect::<Vec<&str>>(), vec!["ECHO", "SHELL"]);
+
+        let mut registry = ServiceRegistry::from_options(&options);
+        assert_eq!(registry.restrict(options.allowed_services.as_deref()), Err(String::from("Unknown service \"ECHO\". Expected one of AWS, DOCKER, SHELL")));
+
+        let options = UserOptions::from_config(crate::ConfigFile::default()).unwrap();
+        let mut registry = ServiceRegistry::from_options(&options);
+        registry.register("echo", Echo);
+        assert_eq!(registry.restrict(options.allowed_services.as_deref()), Ok(()));
+        assert_eq!(registry.names().collect::<Vec<&str>>(), vec!["AWS", "DOCKER", "ECHO", "SHELL"]);
+    }
+
+    #[test]
+    fn panicking_handlers_still_answer() {
+        struct Broken;
+
+        impl ServiceHandler for Broken {
+            fn handle(&self, _: &Request) -> Vec<NormanPacket> {
+                panic!("in-house service bug");
+            }
+        }
+
+        let mut registry = ServiceRegistry::new();
+        registry.register("broken", Broken);
+        let packet = request(Service::new("BROKEN").unwrap(), "anything");
+        let invocation = Invocation::Direct(vec![String::from("anything")]);
+        let responses = dispatch(registry.get("BROKEN").unwrap(), &Request{packet: &packet, invocation: &invocation, peer: "test", run_as: &RunAs::default(), background: &background()});
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].meta.status, Status::INTERNAL_ERROR);
+        assert_eq!(responses[0].data.data, "The BROKEN service failed unexpectedly");
+    }
+
+    #[test]
+    fn text_replies_keep_stderr_and_exit_code() {
+        let packet = request(Service::SHELL, "make");
+        let failed = output_response(&packet, CommandOutput{stdout: String::from("building\n"), stderr: String::from("error: no rule"), exit_code: 2});
+
+        let fragments = into_fragments(vec![failed.clone(), failed], WireFormat::Text, 64);
+        assert_eq!(fragments.len(), 1);
+
+        let text: NormanPacket = fragments[0].as_string().parse().unwrap();
+        assert_eq!(text.meta.status, Status::INTERNAL_ERROR);
+        assert_eq!(text.data.data, "building\nerror: no rule\nexit code 2\nbuilding\nerror: no rule\nexit code 2\n");
+
+        let fine = output_response(&packet, CommandOutput{stdout: String::from("done\n"), stderr: String::new(), exit_code: 0});
+        let fragments = into_fragments(vec![fine], WireFormat::Text, 64);
+        assert_eq!(fragments[0].data.data, "done\n");
+    }
+
+    #[test]
+    fn text_replies_always_parse() {
+        let packet = request(Service::SHELL, "ps aux").with_uid(9);
+        let piped = output_response(&packet, CommandOutput{stdout: String::from("root 1 /sbin/init | tee\n"), stderr: String::new(), exit_code: 0}).with_uid(9);
+
+        let fragments = into_fragments(vec![piped], WireFormat::Text, 64);
+        let text: NormanPacket = fragments[0].to_text().unwrap().parse().unwrap();
+        assert_eq!(text.meta.status, Status::UNSUPPORTED);
+        assert_eq!(text.meta.uid, 9);
+        assert!(text.data.data.contains("use the framed format"));
+    }
+
+    #[test]
+    fn shell_service_runs_commands() {
+        let packet = request(Service::SHELL, "echo hi");
+        let invocation = Invocation::Direct(vec![String::from("echo"), String::from("hi")]);
+        let responses = ShellService.handle(&Request{packet: &packet, invocation: &invocation, peer: "test", run_as: &RunAs::default(), background: &background()});
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].meta.req_type, RequestType::RETURN);
+        assert_eq!(responses[0].data.data, "hi\n");
+        assert_eq!(responses[0].data.exit_code, Some(0));
+    }
+
+    #[test]
+    fn fire_and_forget_is_limited() {
+        let mut packet = request(Service::SHELL, "sleep 5");
+        packet.header.return_output = false;
+        let invocation = Invocation::Direct(vec![String::from("sleep"), String::from("5")]);
+        let background = Background::new(1, std::sync::Arc::new(crate::ThreadPool::new(1)));
+        let _held = background.reserve().unwrap();
+
+        let responses = ShellService.handle(&Request{packet: &packet, invocation: &invocation, peer: "test", run_as: &RunAs::default(), background: &background});
+        assert_eq!(responses[0].meta.status, Status::TOO_LARGE);
+        assert_eq!(background.running(), 1);
+    }
+
+    #[test]
+    fn backends_refuse_shells() {
+        let packet = request(Service::DOCKER, "list");
+        let invocation = Invocation::Shell{shell: String::from("sh"), command: String::from("list")};
+        let responses = Docker::new("/nonexistent/docker.sock").handle(&Request{packet: &packet, invocation: &invocation, peer: "test", run_as: &RunAs::default(), background: &background()});
+
+        assert_eq!(responses[0].meta.status, Status::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,437 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use norman_protocol::{load_certs, load_key};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+/// Where to find the certificates for serving over TLS.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TlsOptions {
+    /// PEM file holding the server's certificate chain.
+    pub cert: String,
+    /// PEM file holding the private key for `cert`.
+    pub key: String,
+    /// PEM file holding the CA that client certificates must be signed by.
+    /// Clients don't need a certificate when this is unset.
+    pub client_ca: Option<String>,
+}
+
+impl TlsOptions {
+    /// Load the certificates and build the rustls config for accepting connections.
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, String> {
+        let certs = load_certs(&self.cert)?;
+        let key = load_key(&self.key)?;
+
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert).map_err(|error| format!("Problem using client CA {}: {}", path, error))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(|error| format!("Problem using client CA {}: {}", path, error))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            },
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(certs, key).map_err(|error| format!("Problem using certificate {}: {}", self.cert, error))?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// A connection from a client, over plain TCP or TLS.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Wrap a newly accepted stream, finishing the TLS handshake first when `tls` is set.
+    ///
+    /// A client that stalls the handshake for longer than `timeout` is given up on.
+    pub fn accept(mut stream: TcpStream, tls: Option<&Arc<ServerConfig>>, timeout: Duration) -> io::Result<Connection> {
+        let config = match tls {
+            Some(config) => config,
+            None => return Ok(Connection::Plain(stream)),
+        };
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut connection = ServerConnection::new(Arc::clone(config)).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        Ok(Connection::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Split the connection in two, so replies can be written while the next request is being read.
+    ///
+    /// A TLS session can't be split, so its halves share it and each only
+    /// holds it while handing bytes to or taking them from it. Neither holds
+    /// it while waiting on the peer for more.
+    pub fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Connection::Plain(stream) => {
+                let writer = stream.try_clone()?;
+                Ok((ReadHalf::Plain(stream), WriteHalf::Plain(writer)))
+            },
+            Connection::Tls(stream) => {
+                let StreamOwned{conn, sock} = *stream;
+                let writer = sock.try_clone()?;
+                let session = Arc::new(Mutex::new(conn));
+                Ok((ReadHalf::Tls{stream: sock, session: Arc::clone(&session), incoming: Vec::new()}, WriteHalf::Tls{stream: writer, session}))
+            },
+        }
+    }
+
+    /// The common name on the certificate the client presented, if any.
+    pub fn peer_name(&self) -> Option<String> {
+        let certs = match self {
+            Connection::Plain(_) => return None,
+            Connection::Tls(stream) => stream.conn.peer_certificates()?,
+        };
+        let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?).ok()?;
+        let name = cert.subject().iter_common_name().next()?.as_str().ok()?.to_string();
+        Some(name)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The half of a split connection that requests are read from.
+pub enum ReadHalf {
+    Plain(TcpStream),
+    Tls {
+        stream: TcpStream,
+        session: Arc<Mutex<ServerConnection>>,
+        /// Bytes read off the socket that the session hasn't taken yet.
+        incoming: Vec<u8>,
+    },
+}
+
+/// The half of a split connection that replies are written to.
+pub enum WriteHalf {
+    Plain(TcpStream),
+    Tls {
+        stream: TcpStream,
+        session: Arc<Mutex<ServerConnection>>,
+    },
+}
+
+impl ReadHalf {
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            ReadHalf::Plain(stream) | ReadHalf::Tls{stream, ..} => stream,
+        }
+    }
+}
+
+impl WriteHalf {
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            WriteHalf::Plain(stream) | WriteHalf::Tls{stream, ..} => stream,
+        }
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stream, session, incoming) = match self {
+            ReadHalf::Plain(stream) => return stream.read(buf),
+            ReadHalf::Tls{stream, session, incoming} => (stream, session, incoming),
+        };
+
+        loop {
+            {
+                let mut session = session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {},
+                    result => return result,
+                }
+
+                if !incoming.is_empty() {
+                    let taken = session.read_tls(&mut incoming.as_slice())?;
+                    incoming.drain(..taken);
+                    let processed = session.process_new_packets();
+                    //Send anything the session has to say back, alerts about bad records included
+                    send_tls(&mut session, stream)?;
+                    processed.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0; 16 * 1024];
+            let read = stream.read(&mut chunk)?;
+            if read == 0 {
+                //Let the session know the peer has gone, so it can tell a clean close from a cut off one
+                let mut session = session.lock().unwrap();
+                session.read_tls(&mut io::empty())?;
+                session.process_new_packets().map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                return session.reader().read(buf);
+            }
+            incoming.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            WriteHalf::Plain(stream) => stream.write(buf),
+            WriteHalf::Tls{stream, session} => {
+                let mut session = session.lock().unwrap();
+                let written = session.writer().write(buf)?;
+                send_tls(&mut session, stream)?;
+                Ok(written)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            WriteHalf::Plain(stream) => stream.flush(),
+            WriteHalf::Tls{stream, session} => {
+                let mut session = session.lock().unwrap();
+                session.writer().flush()?;
+                send_tls(&mut session, stream)?;
+                stream.flush()
+            },
+        }
+    }
+}
+
+/// Write out everything the session has waiting to go to the peer.
+fn send_tls(session: &mut ServerConnection, mut stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut stream)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection};
+
+    struct TestPki {
+        dir: PathBuf,
+        ca: Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl TestPki {
+        fn new(name: &str) -> TestPki {
+            let dir = std::env::temp_dir().join(format!("norman-server-tls-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "norman test CA");
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            TestPki{dir, ca, ca_key}
+        }
+
+        /// Issue a certificate and return the paths of it and its key.
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+            let mut params = CertificateParams::new(vec![String::from("localhost")]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+            let cert_path = self.path(&format!("{}.pem", name));
+            let key_path = self.path(&format!("{}.key", name));
+            fs::write(&cert_path, cert.pem()).unwrap();
+            fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_str().unwrap().to_string()
+        }
+
+        fn client_config(&self, identity: Option<(String, String)>) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(load_certs(&self.path("ca.pem")).unwrap().remove(0)).unwrap();
+            let builder = ClientConfig::builder().with_root_certificates(roots);
+
+            Arc::new(match identity {
+                Some((cert, key)) => builder.with_client_auth_cert(load_certs(&cert).unwrap(), load_key(&key).unwrap()).unwrap(),
+                None => builder.with_no_client_auth(),
+            })
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Accept one connection, echo a line back over it and return the client's name.
+    fn serve_once(config: Arc<ServerConfig>) -> (u16, thread::JoinHandle<io::Result<Option<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let mut connection = Connection::accept(stream, Some(&config), Duration::from_secs(5))?;
+            let mut buf = [0; 5];
+            connection.read_exact(&mut buf)?;
+            connection.write_all(&buf)?;
+            connection.flush()?;
+            Ok(connection.peer_name())
+        });
+
+        (port, server)
+    }
+
+    fn talk(port: u16, config: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+        let connection = ClientConnection::new(config, ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(("127.0.0.1", port))?);
+        stream.write_all(b"hello")?;
+        let mut buf = vec![0; 5];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn mutual_tls_identifies_client() {
+        let pki = TestPki::new("mutual");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = pki.issue("alice-laptop", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let options = TlsOptions{cert, key, client_ca: Some(pki.path("ca.pem"))};
+        let (port, server) = serve_once(options.server_config().unwrap());
+
+        assert_eq!(talk(port, pki.client_config(Some(client))).unwrap(), b"hello");
+        assert_eq!(server.join().unwrap().unwrap(), Some(String::from("alice-laptop")));
+    }
+
+    #[test]
+    fn client_certificate_required_when_ca_set() {
+        let pki = TestPki::new("required");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let options = TlsOptions{cert, key, client_ca: Some(pki.path("ca.pem"))};
+        let (port, server) = serve_once(options.server_config().unwrap());
+
+        assert!(talk(port, pki.client_config(None)).is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn server_only_tls() {
+        let pki = TestPki::new("server-only");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let options = TlsOptions{cert, key, client_ca: None};
+        let (port, server) = serve_once(options.server_config().unwrap());
+
+        assert_eq!(talk(port, pki.client_config(None)).unwrap(), b"hello");
+        assert_eq!(server.join().unwrap().unwrap(), None);
+    }
+
+    #[test]
+    fn split_halves_write_while_reading() {
+        let pki = TestPki::new("split");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let config = TlsOptions{cert, key, client_ca: None}.server_config().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut reader, mut writer) = Connection::accept(stream, Some(&config), Duration::from_secs(5)).unwrap().split().unwrap();
+
+            //The reader is left waiting on the client while the writer speaks first
+            let reading = thread::spawn(move || {
+                let mut buf = [0; 5];
+                reader.read_exact(&mut buf).map(|_| buf)
+            });
+            writer.write_all(b"first").unwrap();
+            let buf = reading.join().unwrap().unwrap();
+            writer.write_all(&buf).unwrap();
+        });
+
+        let connection = ClientConnection::new(pki.client_config(None), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"first");
+
+        stream.write_all(b"hello").unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stalled_handshakes_time_out() {
+        let pki = TestPki::new("stalled");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        let config = TlsOptions{cert, key, client_ca: None}.server_config().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let error = Connection::accept(stream, Some(&config), Duration::from_millis(100)).err().unwrap();
+        assert!(matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn bad_certificate_paths() {
+        let pki = TestPki::new("bad-paths");
+        let (cert, key) = pki.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+
+        assert!(TlsOptions{cert: String::from("/nonexistent/cert.pem"), key: key.clone(), client_ca: None}.server_config().is_err());
+        assert!(TlsOptions{cert: key.clone(), key: key.clone(), client_ca: None}.server_config().is_err());
+        assert!(TlsOptions{cert: cert.clone(), key: cert, client_ca: None}.server_config().is_err());
+    }
+}